@@ -1,19 +1,71 @@
 use glob::glob;
-use rusqlite::Connection;
-use tempfile::NamedTempFile;
-use directories::ProjectDirs;
-use std::{collections::HashMap, path::Path, io::{self, Write}, path::PathBuf, env, fs};
-use super::{discord, matrix, generic, DBFile};
+use chrono::NaiveDateTime;
+use rusqlite::{types::ValueRef, Connection, OptionalExtension};
+use zeroize::Zeroizing;
+use std::{collections::HashMap, path::{Component, Path, PathBuf}, fs};
+use super::{
+    decrypted::{Database, DecryptedBackup},
+    encryption::{self, Progress},
+    error::{Error, Result},
+    filter,
+    format::{ArchiveFormat, AssetType, Page, Registry, Search, SearchMatch, SearchOrder},
+    index,
+    session::Session,
+};
+
+// The directory the backups are in, along with the formats they can be read with
+pub struct Refrigerator {
+    pub path: PathBuf,
+    pub registry: Registry,
+}
 
 #[derive(Serialize)]
 pub struct SelectionContext<'a> {
     backup_path: &'a str,
     chat_id: &'a str,
+    // Whether the selected backup is an encrypted one that has been decrypted
+    unlocked: bool,
     backups: Vec<[String; 2]>,
+    broken_backups: Vec<[String; 2]>, // path, reason
     chats: Vec<[String; 2]>,
     mapped_chats: String,
 }
 
+// A search result, with the chat it was found in
+#[derive(Serialize)]
+pub struct SearchHit {
+    pub chat_id: String,
+    pub chat_name: String,
+    // The text around the matched terms, with them highlighted
+    pub snippet: Option<String>,
+    pub message: Message,
+}
+
+// A page of search results
+#[derive(Serialize)]
+pub struct SearchResults {
+    // The number of hits on all the pages
+    pub total: usize,
+    pub hits: Vec<SearchHit>,
+}
+
+// A search result from any of the backups in the refrigerator
+#[derive(Serialize)]
+pub struct GlobalSearchHit {
+    pub backup_path: String,
+    pub backup_name: String,
+    #[serde(flatten)]
+    pub hit: SearchHit,
+}
+
+#[derive(Serialize)]
+pub struct GlobalSearchResults {
+    pub total: usize,
+    pub hits: Vec<GlobalSearchHit>,
+    // Backups that couldn't be searched, with the reason
+    pub failed: Vec<[String; 2]>,
+}
+
 #[derive(Serialize)]
 pub struct Message {
     pub sequential_id: String, // This is useful because sometimes message_id is not sequential (like in matrix)
@@ -55,68 +107,74 @@ impl Default for Message {
     }
 }
 
+// A backup in the refrigerator
+#[derive(Serialize)]
+pub struct BackupSummary {
+    pub path: String,
+    pub name: String,
+    // The name of the format, like "discord"
+    pub format: String,
+    pub encrypted: bool,
+    // Whether the backup can be read in the session, which unencrypted backups always can
+    pub unlocked: bool,
+}
+
+#[derive(Serialize)]
+pub struct Chat {
+    pub id: String,
+    pub name: String,
+    pub topic: String,
+}
+
+// Someone who sent messages, and when they sent the first and last ones
+#[derive(Serialize)]
+pub struct User {
+    pub name: String,
+    pub messages: u64,
+    pub first_message: String,
+    pub last_message: String,
+}
+
+// The messages around the first message at or after a time
+#[derive(Serialize)]
+pub struct DateJump {
+    // The message to scroll to, which is None if the chat has no messages that the format shows
+    sequential_id: Option<u64>,
+    messages: Vec<Message>,
+}
+
 #[derive(Serialize)]
 pub struct ChatContext<'a> {
     name: String,
     topic: String,
     messages: Vec<Message>,
+    // The message the messages are around, if it's not the latest ones
+    message_id: Option<String>,
     selection_context: Option<SelectionContext<'a>>,
 }
 
-impl Default for ChatContext<'_> {
-    fn default() -> Self {
-        ChatContext {
-            name: String::new(),
-            topic: String::new(),
-            messages: Vec::new(),
-            selection_context: None,
-        }
-    }
-}
+// Messages are paged with their (created_timestamp, ROWID) as the cursor, because the rows of a
+// backup aren't necessarily in chronological order, like when exports have been merged. $2 is the
+// ROWID of the message the cursor is at
+const LATEST_MESSAGES: &str = "SELECT * FROM ({} chat = $1 ORDER BY created_timestamp DESC, ROWID DESC LIMIT 100) ORDER BY created_timestamp, ROWID";
+const MESSAGES_ABOVE: &str = "SELECT * FROM ({} chat = $1 AND (created_timestamp, ROWID) < (SELECT created_timestamp, ROWID FROM messages WHERE ROWID = $2)
+    ORDER BY created_timestamp DESC, ROWID DESC LIMIT 100) ORDER BY created_timestamp, ROWID";
+const MESSAGES_BELOW: &str = "{} chat = $1 AND (created_timestamp, ROWID) > (SELECT created_timestamp, ROWID FROM messages WHERE ROWID = $2)
+    ORDER BY created_timestamp, ROWID LIMIT 100";
+const MESSAGES_AFTER: &str = "{} chat = ?1 AND (?2 IS NULL OR (created_timestamp, ROWID) > (SELECT created_timestamp, ROWID FROM messages WHERE ROWID = ?2))
+    ORDER BY created_timestamp, ROWID LIMIT ?3";
+// Like MESSAGES_AFTER, but starting with the message
+const MESSAGES_FROM: &str = "{} chat = ?1 AND (?2 IS NULL OR (created_timestamp, ROWID) >= (SELECT created_timestamp, ROWID FROM messages WHERE ROWID = ?2))
+    ORDER BY created_timestamp, ROWID LIMIT ?3";
+const MESSAGES_AROUND: &str = "SELECT * FROM ({} chat = $1 AND (created_timestamp, ROWID) <= (SELECT created_timestamp, ROWID FROM messages WHERE ROWID = $2)
+    ORDER BY created_timestamp DESC, ROWID DESC LIMIT 50)
+    UNION SELECT * FROM ({} chat = $1 AND (created_timestamp, ROWID) > (SELECT created_timestamp, ROWID FROM messages WHERE ROWID = $2)
+    ORDER BY created_timestamp, ROWID LIMIT 50) ORDER BY created_timestamp, ROWID";
 
-pub fn refrigerator() -> String {
-    // First priority is the refrigerator environment variable
-    // If that doesn't exist, try a directory named `refrigerator` in the working directory
-    let path = env::var("REFRIGERATOR").unwrap_or(String::from("refrigerator"));
-    if Path::new(&path).is_dir() {
-        // If the refrigerator path is a valid directory
-        path
-    } else {
-        // If both of those failed, get the refrigerator path from the config file
-        let project_dirs = ProjectDirs::from("", "", "cheesecake").unwrap();
-        let config_dir = project_dirs.config_dir();
-        let mut new_path = String::new();
-        // The config file already exists
-        if config_dir.join("refrigerator_path").exists() {
-            // Read the config file
-            new_path = fs::read_to_string(config_dir.join("refrigerator_path")).unwrap().trim().to_string();
-            if Path::new(&new_path).is_dir() {
-                // If the path specified in the config file is a valid directory, return it
-                return new_path;
-            }
-        } else {
-            // The config file doesn't exist yet
-            // Get a path to test out
-            print!("Path `{}` is not valid. Either create the directory and restart cheesecake, or provide a new path here: ", path);
-            io::stdout().flush().unwrap();
-            // Get the new path
-            io::stdin().read_line(&mut new_path).unwrap();
-        }
-        // Keep asking for a new path until we get a valid one
-        while !Path::new(new_path.trim()).exists() {
-            print!("Path `{}` is not valid. Please enter a valid path: ", new_path.trim());
-            io::stdout().flush().unwrap();
-            // Reset `new_path`
-            new_path = String::new();
-            // Get the new path
-            io::stdin().read_line(&mut new_path).unwrap();
-        }
-        // Store the new path in the config file
-        fs::create_dir_all(config_dir).unwrap();
-        fs::write(config_dir.join("refrigerator_path"), new_path.trim()).unwrap();
-        return new_path.trim().to_string();
-    }
-}
+// Every column of the messages table, which differ between formats, along with the sequential ID
+const RAW_MESSAGE: &str = "SELECT ROWID AS sequential_id, * FROM messages WHERE";
+// How many messages ChatMessages fetches at a time
+const MESSAGE_PAGE: usize = 500;
 
 pub fn day_separator(timestamp: chrono::DateTime<chrono::Local>) -> Message {
     Message {
@@ -126,274 +184,749 @@ pub fn day_separator(timestamp: chrono::DateTime<chrono::Local>) -> Message {
     }
 }
 
-fn backup_type(backup_path: &str) -> String {
-    let json: serde_json::Value = serde_json::from_str(&fs::read_to_string(
-        Path::new(&refrigerator()).join(backup_path).join("info.json")
-    ).unwrap()).unwrap();
-    json["type"].as_str().unwrap().to_owned()
+// Whether the path of a backup is a single directory name, so that joining it onto the refrigerator
+// can't reach anything outside it
+pub fn is_backup_path(backup_path: &str) -> bool {
+    let mut components = Path::new(backup_path).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(name)), None) => name == backup_path,
+        _ => false,
+    }
+}
+
+// The path of the info.json of a backup
+pub fn info_path(refrigerator: &Path, backup_path: &str) -> PathBuf {
+    refrigerator.join(backup_path).join("info.json")
 }
 
-fn chat_list(conn: Connection) -> Vec<[String; 2]> {
-    let mut chats = Vec::new();
-    let mut statement = conn.prepare("SELECT id, name FROM chats").unwrap();
-    let mut rows = statement.query([]).unwrap();
-    while let Some(chat) = rows.next().unwrap() {
-        chats.push([
-            chat.get(0).unwrap(),
-            chat.get(1).unwrap(),
-        ]);
+// Reads the info.json of a backup
+// Everything that reads a backup starts here, so this is where paths that aren't backups are caught
+pub fn read_info(refrigerator: &Path, backup_path: &str) -> Result<serde_json::Value> {
+    if !is_backup_path(backup_path) {
+        return Err(Error::InvalidBackupPath(backup_path.to_owned()));
     }
-    chats
+    let info = serde_json::from_str(&fs::read_to_string(info_path(refrigerator, backup_path))?)?;
+    Ok(info)
+}
+
+// Finds the format of the backup from its info.json
+fn info_format<'a>(registry: &'a Registry, info: &serde_json::Value) -> Result<&'a dyn ArchiveFormat> {
+    let backup_type = info["type"].as_str().ok_or(Error::InvalidInfo("type"))?;
+    registry.get(backup_type).ok_or_else(|| Error::UnknownFormat(backup_type.to_owned()))
+}
+
+fn backup_format<'a>(refrigerator: &'a Refrigerator, backup_path: &str) -> Result<&'a dyn ArchiveFormat> {
+    info_format(&refrigerator.registry, &read_info(&refrigerator.path, backup_path)?)
+}
+
+fn check_version(info: &serde_json::Value) -> Result<()> {
+    match info["version"].as_str() {
+        Some("0.1.0") => Ok(()),
+        Some(version) => Err(Error::UnsupportedVersion(version.to_owned())),
+        None => Err(Error::InvalidInfo("version")),
+    }
+}
+
+// Reads the name and list of chats of a backup
+// The list of chats is None if the backup is encrypted
+fn read_backup(refrigerator: &Refrigerator, session: &Session, backup_path: &str) -> Result<(String, Option<Vec<[String; 2]>>)> {
+    let info = read_info(&refrigerator.path, backup_path)?;
+    check_version(&info)?;
+    let format = info_format(&refrigerator.registry, &info)?;
+    // The name of the backup (like "Archive 1")
+    let name = info["name"].as_str().ok_or(Error::InvalidInfo("name"))?.to_owned();
+
+    let decrypted = session.decrypted(backup_path);
+    if info["salt"].is_string() && decrypted.is_none() {
+        // The backup is encrypted
+        return Ok((name, None));
+    }
+    // Get the list of chats
+    let conn = open_database(refrigerator, decrypted, backup_path)?;
+    Ok((name, Some(format.chat_list(&conn)?)))
+}
+
+fn backup_summary(refrigerator: &Refrigerator, session: &Session, backup_path: &str) -> Result<BackupSummary> {
+    let info = read_info(&refrigerator.path, backup_path)?;
+    check_version(&info)?;
+    let encrypted = info["salt"].is_string();
+    Ok(BackupSummary {
+        path: backup_path.to_owned(),
+        name: info["name"].as_str().ok_or(Error::InvalidInfo("name"))?.to_owned(),
+        format: info_format(&refrigerator.registry, &info)?.name().to_owned(),
+        encrypted,
+        unlocked: !encrypted || session.decrypted(backup_path).is_some(),
+    })
+}
+
+// Lists the backups in the refrigerator, along with the ones that can't be read and why
+pub fn backups(refrigerator: &Refrigerator, session: &Session) -> (Vec<BackupSummary>, Vec<[String; 2]>) {
+    let mut backups = Vec::new();
+    let (backup_paths, mut broken_backups) = backup_paths(&refrigerator.path);
+    for backup_path in backup_paths {
+        match backup_summary(refrigerator, session, &backup_path) {
+            Ok(backup) => backups.push(backup),
+            Err(e) => broken_backups.push([backup_path, e.to_string()]),
+        }
+    }
+    (backups, broken_backups)
+}
+
+// Finds the paths of all the cheesecakes in the refrigerator (like "123456789123456789"), along
+// with the ones that couldn't be read and why
+pub fn backup_paths(refrigerator: &Path) -> (Vec<String>, Vec<[String; 2]>) {
+    let mut paths = Vec::new();
+    let mut broken_paths = Vec::new();
+    let pattern = refrigerator.join("*/info.json");
+    for entry in glob(&pattern.to_string_lossy()).into_iter().flatten() {
+        let path = match entry {
+            Ok(path) => path,
+            Err(e) => {
+                let name = e.path().parent().and_then(Path::file_name).unwrap_or_default();
+                broken_paths.push([name.to_string_lossy().into_owned(), e.error().to_string()]);
+                continue;
+            }
+        };
+        if let Some(name) = path.parent().and_then(Path::file_name) {
+            paths.push(name.to_string_lossy().into_owned());
+        }
+    }
+    (paths, broken_paths)
 }
 
 // Creates context with information to select a chat from
-pub fn selection_context<'a>(db_file: &'a DBFile, backup_path: &'a str, chat_id: &'a str) -> SelectionContext<'a> {
+pub fn selection_context<'a>(refrigerator: &Refrigerator, session: &Session, backup_path: &'a str, chat_id: &'a str) -> SelectionContext<'a> {
     let mut selected_backup = 0;
     let mut backups = Vec::new();
+    let (backup_paths, mut broken_backups) = backup_paths(&refrigerator.path);
     let mut mapped_chats = HashMap::new();
     // Iterate over all the cheesecakes found in the refrigerator
-    for entry in glob(
-        Path::new(&refrigerator())
-            .join("*/info.json")
-            .to_str()
-            .unwrap(),
-    ).unwrap() {
-        match entry {
-            Ok(path) => {
-                let json: serde_json::Value =
-                    serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
-                if json["version"] == "0.1.0" && ["discord", "matrix", "generic"].contains(&json["type"].as_str().unwrap()) {
-                    // The path of the backup (like "123456789123456789")
-                    let current_backup_path = path
-                        .parent()
-                        .unwrap()
-                        .file_name()
-                        .unwrap()
-                        .to_str()
-                        .unwrap()
-                        .to_owned();
-                    // The name of the backup (like "Archive 1")
-                    let current_backup_name = json["name"].as_str().unwrap().to_owned();
-                    backups.push([current_backup_path.clone(), current_backup_name]);
-                    if backup_path == current_backup_path {
-                        selected_backup = backups.len() - 1;
-                    }
-
-                    if json["salt"].is_string() && db_file.backup_path != current_backup_path {
-                        // The backup is encrypted, insert an empty list of chats
-                        mapped_chats.insert(current_backup_path, Vec::new());
-                    } else {
-                        let database_path;
-                        if json["salt"].is_string() {
-                            // The backup is decrypted
-                            database_path = db_file.file.as_ref().unwrap().path().into();
-                        } else {
-                            database_path = path.parent().unwrap().join("backup.db");
-                        }
-                        // Get the list of chats
-                        let conn = Connection::open(database_path).unwrap();
-                        let chats = chat_list(conn);
-                        // If there were no chats, don't include the backup
-                        // We don't insert an empty list because that'll appear like an encrypted
-                        // backup
-                        if chats.len() > 0 {
-                            mapped_chats.insert(current_backup_path, chats);
-                        }
-                    }
+    for current_backup_path in backup_paths {
+        match read_backup(refrigerator, session, &current_backup_path) {
+            Ok((current_backup_name, chats)) => {
+                match chats {
+                    // If there were no chats, don't include the backup
+                    // We don't insert an empty list because that'll appear like an encrypted backup
+                    Some(chats) if chats.is_empty() => continue,
+                    Some(chats) => mapped_chats.insert(current_backup_path.clone(), chats),
+                    // The backup is encrypted, insert an empty list of chats
+                    None => mapped_chats.insert(current_backup_path.clone(), Vec::new()),
+                };
+                backups.push([current_backup_path.clone(), current_backup_name]);
+                if backup_path == current_backup_path {
+                    selected_backup = backups.len() - 1;
                 }
             }
-            Err(e) => println!("{:?}", e),
+            Err(e) => broken_backups.push([current_backup_path, e.to_string()]),
         }
     }
-    let chats = if backups.len() == 0 {
-        Vec::new()
-    } else {
-        mapped_chats
-            .get(&backups[selected_backup][0])
-            .unwrap()
-            .to_owned()
+    let chats = match backups.get(selected_backup) {
+        Some(backup) => mapped_chats.get(&backup[0]).cloned().unwrap_or_default(),
+        None => Vec::new(),
     };
     SelectionContext {
         backup_path,
         chat_id,
+        unlocked: session.decrypted(backup_path).is_some(),
         backups,
+        broken_backups,
         chats,
-        mapped_chats: serde_json::to_string(&mapped_chats).unwrap(),
-    }
-}
-
-pub fn decrypt(db_file: &mut DBFile, password: &str) -> Vec<[String; 2]> {
-    let info_path = Path::new(&refrigerator())
-        .join(&db_file.backup_path)
-        .join("info.json");
-    let info: serde_json::Value =
-        serde_json::from_str(&fs::read_to_string(&info_path).unwrap()).unwrap();
-    if let Some(salt) = info["salt"].as_str() {
-        if let Some(iterations) = info["iterations"].as_u64() {
-            // Generate a key with the given password
-            let mut key = [0; 32];
-            openssl::pkcs5::pbkdf2_hmac(
-                password.as_bytes(),
-                base64::decode_config(salt, base64::URL_SAFE).unwrap().as_slice(),
-                iterations as usize,
-                openssl::hash::MessageDigest::sha256(),
-                &mut key,
-            ).unwrap();
+        mapped_chats: serde_json::to_string(&mapped_chats).unwrap_or_default(),
+    }
+}
 
-            // It'll be None if the generated key was wrong
-            if let Some(fernet) = fernet::Fernet::new(&base64::encode(key)) {
-                let ciphertext = std::fs::read_to_string(info_path.parent().unwrap().join("backup.db")).unwrap();
-                let mut file = NamedTempFile::new().unwrap();
-                if let Ok(decrypted) = fernet.decrypt(&ciphertext) {
-                    // If the file was successfully decrypted with the generated key
-                    file.write_all(decrypted.as_slice()).unwrap();
-                    // Open a connection to the decrypted database
-                    let conn = Connection::open(file.path()).unwrap();
-                    // Store the NamedTempFile instance to State so that the file doesn't get destroyed
-                    db_file.file = Some(file);
-                    // Return the list of chats
-                    return chat_list(conn);
-                }
+// Decrypts the backup and returns the decrypted copy along with the list of chats in it
+// With `in_memory`, the decrypted database is never written to disk
+pub fn decrypt(refrigerator: &Refrigerator, backup_path: &str, password: &str, in_memory: bool, progress: &Progress) -> Result<(DecryptedBackup, Vec<[String; 2]>)> {
+    let format = backup_format(refrigerator, backup_path)?;
+    let key = encryption::backup_key(&refrigerator.path, backup_path, password)?;
+    let decrypted = DecryptedBackup::decrypt(&database_path(&refrigerator.path, backup_path), key, in_memory, progress)?;
+    let chats = format.chat_list(&*decrypted.open()?)?;
+    // The decrypted copy is wiped when it is dropped, so it has to be kept in the session
+    Ok((decrypted, chats))
+}
+
+// The path of the database file in the backup
+pub fn database_path(refrigerator: &Path, backup_path: &str) -> PathBuf {
+    refrigerator.join(backup_path).join("backup.db")
+}
+
+// Opens the decrypted copy of the backup if there is one, and the backup itself otherwise
+fn open_database<'a>(refrigerator: &Refrigerator, decrypted: Option<&'a DecryptedBackup>, backup_path: &str) -> Result<Database<'a>> {
+    match decrypted {
+        Some(decrypted) => decrypted.open(),
+        None => {
+            if read_info(&refrigerator.path, backup_path)?["salt"].is_string() {
+                return Err(Error::Locked);
             }
+            Ok(Database::Owned(Connection::open(database_path(&refrigerator.path, backup_path))?))
         }
     }
-    Vec::new()
 }
 
-fn database_path(db_file: &Option<NamedTempFile>, backup_path: &str) -> PathBuf {
-    match db_file {
-        Some(file) => file.path().into(),
-        None => Path::new(&refrigerator()).join(backup_path).join("backup.db"),
+// Decrypts an asset of a backup whose assets are encrypted
+// Returns None if the asset isn't encrypted, so it can be served as it is
+pub fn decrypted_asset(refrigerator: &Refrigerator, session: &Session, backup_path: &str, asset_type: AssetType, asset_path: &Path) -> Result<Option<Zeroizing<Vec<u8>>>> {
+    if !encryption::has_encrypted_assets(&read_info(&refrigerator.path, backup_path)?) {
+        return Ok(None);
+    }
+    let path = encryption::encrypted_asset_path(
+        &refrigerator.path.join(backup_path).join(asset_type.dir()).join(asset_path)
+    );
+    if !path.is_file() {
+        // The asset hasn't been encrypted yet
+        return Ok(None);
     }
+    let decrypted = session.decrypted(backup_path).ok_or(Error::Locked)?;
+    Ok(Some(decrypted.key.decrypt_to_vec(&path)?))
 }
 
-pub fn chat<'a>(db_file: &'a DBFile, backup_path: &'a str, chat_id: &'a str) -> ChatContext<'a> {
-    let populate_messages = match backup_type(backup_path).as_str() {
-        "discord" => discord::populate_messages,
-        "matrix" => matrix::populate_messages,
-        "generic" => generic::populate_messages,
-        _ => return ChatContext::default(),
-    };
-    let database_path = &database_path(&db_file.file, backup_path);
-    let messages = populate_messages(
-        database_path,
+// Gets the name and topic of a chat
+fn chat_details(conn: &Connection, chat_id: &str) -> Result<(String, String)> {
+    let mut statement = conn.prepare("SELECT name, topic FROM chats WHERE id = $1")?;
+    let mut rows = statement.query([chat_id])?;
+    match rows.next()? {
+        Some(row) => Ok((row.get(0)?, row.get::<_, Option<String>>(1)?.unwrap_or_default())),
+        None => Err(Error::ChatNotFound(chat_id.to_owned())),
+    }
+}
+
+// Lists the chats in the backup along with their topics
+pub fn chats(refrigerator: &Refrigerator, decrypted: Option<&DecryptedBackup>, backup_path: &str) -> Result<Vec<Chat>> {
+    let format = backup_format(refrigerator, backup_path)?;
+    let conn = open_database(refrigerator, decrypted, backup_path)?;
+    let mut chats = Vec::new();
+    for [id, name] in format.chat_list(&conn)? {
+        let (_, topic) = chat_details(&conn, &id)?;
+        chats.push(Chat {id, name, topic});
+    }
+    Ok(chats)
+}
+
+pub fn find_chat(refrigerator: &Refrigerator, decrypted: Option<&DecryptedBackup>, backup_path: &str, chat_id: &str) -> Result<Chat> {
+    let (name, topic) = chat_details(&*open_database(refrigerator, decrypted, backup_path)?, chat_id)?;
+    Ok(Chat {id: chat_id.to_owned(), name, topic})
+}
+
+// Lists the people who sent messages in the chat, or in the whole backup if `chat_id` is None,
+// starting with whoever sent the most
+pub fn users(refrigerator: &Refrigerator, decrypted: Option<&DecryptedBackup>, backup_path: &str, chat_id: Option<&str>) -> Result<Vec<User>> {
+    let conn = open_database(refrigerator, decrypted, backup_path)?;
+    if let Some(chat_id) = chat_id {
+        chat_details(&conn, chat_id)?;
+    }
+    let mut statement = conn.prepare(
+        "SELECT name, COUNT(*) AS count, MIN(created_timestamp), MAX(created_timestamp) FROM messages
+        WHERE name IS NOT NULL AND (?1 IS NULL OR chat = ?1) GROUP BY name ORDER BY count DESC, name"
+    )?;
+    let rows = statement.query_map([chat_id], |row| Ok(User {
+        name: row.get(0)?,
+        messages: row.get(1)?,
+        first_message: row.get(2)?,
+        last_message: row.get(3)?,
+    }))?;
+    let mut users = Vec::new();
+    for user in rows {
+        users.push(user?);
+    }
+    Ok(users)
+}
+
+// The number of messages in the chat that the format shows, which is how many `chat_messages` goes
+// through
+pub fn message_count(refrigerator: &Refrigerator, decrypted: Option<&DecryptedBackup>, backup_path: &str, chat_id: &str) -> Result<u64> {
+    let format = backup_format(refrigerator, backup_path)?;
+    let conn = open_database(refrigerator, decrypted, backup_path)?;
+    chat_details(&conn, chat_id)?;
+    Ok(conn.query_row(
+        &format!("SELECT COUNT(*) FROM messages WHERE chat = $1 AND ({})", format.shown_condition()),
+        [chat_id],
+        |row| row.get(0),
+    )?)
+}
+
+// Gets the messages in up to `limit` rows of the chat that come after the message with the
+// sequential ID, or the first ones if it's None, the way they're shown in the reader but without day
+// separators
+// Rows that the format doesn't show are left out, so this also returns the sequential ID of the
+// last row, which the next page starts after, unless it was the last row of the chat
+pub fn messages_after(refrigerator: &Refrigerator, decrypted: Option<&DecryptedBackup>, backup_path: &str, chat_id: &str, sequential_id: Option<u64>, limit: usize) -> Result<(Vec<Message>, Option<u64>)> {
+    messages_page(refrigerator, decrypted, backup_path, chat_id, MESSAGES_AFTER, sequential_id, limit)
+}
+
+// Gets a page of messages with MESSAGES_AFTER or MESSAGES_FROM
+fn messages_page(refrigerator: &Refrigerator, decrypted: Option<&DecryptedBackup>, backup_path: &str, chat_id: &str, sql: &str, sequential_id: Option<u64>, limit: usize) -> Result<(Vec<Message>, Option<u64>)> {
+    let format = backup_format(refrigerator, backup_path)?;
+    let conn = open_database(refrigerator, decrypted, backup_path)?;
+    chat_details(&conn, chat_id)?;
+    let messages = format.populate_messages(&conn, &refrigerator.path, backup_path, sql, &[&chat_id, &sequential_id, &(limit as i64)])?;
+    // One more row is fetched to find out if there's a next page
+    let mut statement = conn.prepare(&sql.replace("{}", "SELECT ROWID FROM messages WHERE"))?;
+    let rows = statement
+        .query_map(rusqlite::params![chat_id, sequential_id, limit as i64 + 1], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<u64>>>()?;
+    let next = if rows.len() > limit {rows[..limit].last().copied()} else {None};
+    let messages = messages.into_iter().filter(|message| message.message_type != "day_separator").collect();
+    Ok((messages, next))
+}
+
+// Like `messages_after`, but with the rows of the messages table as they are
+pub fn raw_messages_after(refrigerator: &Refrigerator, decrypted: Option<&DecryptedBackup>, backup_path: &str, chat_id: &str, sequential_id: Option<u64>, limit: usize) -> Result<Vec<serde_json::Value>> {
+    let conn = open_database(refrigerator, decrypted, backup_path)?;
+    chat_details(&conn, chat_id)?;
+    let mut statement = conn.prepare(&MESSAGES_AFTER.replace("{}", RAW_MESSAGE))?;
+    let mut rows = statement.query(rusqlite::params![chat_id, sequential_id, limit as i64])?;
+    let mut messages = Vec::new();
+    while let Some(row) = rows.next()? {
+        messages.push(raw_row(row)?);
+    }
+    Ok(messages)
+}
+
+// Goes through all the messages of a chat from the oldest one, fetching them a page at a time
+// Made with `chat_messages`
+pub struct ChatMessages<'a> {
+    refrigerator: &'a Refrigerator,
+    decrypted: Option<&'a DecryptedBackup>,
+    backup_path: &'a str,
+    chat_id: &'a str,
+    // The sequential ID of the last message that was fetched, or of the last row if none of the rows
+    // on the page were shown
+    cursor: Option<u64>,
+    page: std::vec::IntoIter<Message>,
+    done: bool,
+}
+
+impl Iterator for ChatMessages<'_> {
+    type Item = Result<Message>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(message) = self.page.next() {
+                return Some(Ok(message));
+            }
+            if self.done {
+                return None;
+            }
+            // Whether a message is shown separately depends on the one before it, so the pages after
+            // the first start with the last message of the previous page, which is skipped
+            let page = messages_page(self.refrigerator, self.decrypted, self.backup_path, self.chat_id, MESSAGES_FROM, self.cursor, MESSAGE_PAGE);
+            let (mut messages, next) = match page {
+                Ok(page) => page,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            };
+            if self.cursor.is_some() && messages.first().map(|message| message.sequential_id.parse().ok()) == Some(self.cursor) {
+                messages.remove(0);
+            }
+            // The chat ends when the rows do, even if the last ones weren't shown
+            self.done = next.is_none();
+            self.cursor = messages
+                .last()
+                .and_then(|message| message.sequential_id.parse().ok())
+                .or(next);
+            self.page = messages.into_iter();
+        }
+    }
+}
+
+pub fn chat_messages<'a>(refrigerator: &'a Refrigerator, decrypted: Option<&'a DecryptedBackup>, backup_path: &'a str, chat_id: &'a str) -> ChatMessages<'a> {
+    ChatMessages {
+        refrigerator,
+        decrypted,
         backup_path,
-        "SELECT * FROM ({} chat = $1 ORDER BY created_timestamp DESC LIMIT 100) ORDER BY created_timestamp",
-        &[&chat_id],
-    );
-    // Create a connection to the database
-    let conn = Connection::open(database_path).unwrap();
-    // Get the chat name and topic
-    let mut statement = conn
-        .prepare("SELECT name, topic FROM chats WHERE id = $1")
-        .unwrap();
-    let mut rows = statement.query([chat_id]).unwrap();
-    let chat_details = rows.next().unwrap().unwrap();
-    // Return the ChatContext
-    ChatContext {
-        name: chat_details.get(0).unwrap(),
-        topic: chat_details.get(1).unwrap_or(String::new()),
-        messages,
-        selection_context: Some(selection_context(db_file, backup_path, chat_id)),
+        chat_id,
+        cursor: None,
+        page: Vec::new().into_iter(),
+        done: false,
     }
 }
 
-pub fn jump_chat<'a>(db_file: &'a Option<NamedTempFile>, backup_path: &'a str, chat_id: &'a str, message_id: &Option<String>) -> ChatContext<'static> {
-    let populate_messages = match backup_type(backup_path).as_str() {
-        "discord" => discord::populate_messages,
-        "matrix" => matrix::populate_messages,
-        "generic" => generic::populate_messages,
-        _ => return ChatContext::default(),
-    };
+pub fn message(refrigerator: &Refrigerator, decrypted: Option<&DecryptedBackup>, backup_path: &str, chat_id: &str, message_id: &str) -> Result<Message> {
+    let format = backup_format(refrigerator, backup_path)?;
+    let conn = open_database(refrigerator, decrypted, backup_path)?;
+    chat_details(&conn, chat_id)?;
+    format
+        .populate_messages(&conn, &refrigerator.path, backup_path, "{} chat = $1 AND id = $2", &[&chat_id, &message_id])?
+        .pop()
+        .ok_or_else(|| Error::MessageNotFound(message_id.to_owned()))
+}
+
+pub fn raw_message(refrigerator: &Refrigerator, decrypted: Option<&DecryptedBackup>, backup_path: &str, chat_id: &str, message_id: &str) -> Result<serde_json::Value> {
+    let conn = open_database(refrigerator, decrypted, backup_path)?;
+    chat_details(&conn, chat_id)?;
+    conn.query_row(&format!("{} chat = $1 AND id = $2", RAW_MESSAGE), [chat_id, message_id], |row| Ok(raw_row(row)))
+        .optional()?
+        .unwrap_or_else(|| Err(Error::MessageNotFound(message_id.to_owned())))
+}
+
+fn raw_row(row: &rusqlite::Row) -> Result<serde_json::Value> {
+    let mut object = serde_json::Map::new();
+    for (i, column) in row.as_ref().column_names().into_iter().enumerate() {
+        let value = match row.get_ref(i)? {
+            ValueRef::Null => serde_json::Value::Null,
+            ValueRef::Integer(integer) => integer.into(),
+            ValueRef::Real(real) => real.into(),
+            ValueRef::Text(text) => String::from_utf8_lossy(text).into(),
+            ValueRef::Blob(blob) => base64::encode(blob).into(),
+        };
+        object.insert(column.to_owned(), value);
+    }
+    Ok(object.into())
+}
+
+pub fn chat<'a>(refrigerator: &Refrigerator, session: &Session, backup_path: &'a str, chat_id: &'a str, message_id: &Option<String>) -> Result<ChatContext<'a>> {
+    let mut context = jump_chat(refrigerator, session.decrypted(backup_path), backup_path, chat_id, message_id)?;
+    context.selection_context = Some(selection_context(refrigerator, session, backup_path, chat_id));
+    Ok(context)
+}
+
+pub fn jump_chat(refrigerator: &Refrigerator, decrypted: Option<&DecryptedBackup>, backup_path: &str, chat_id: &str, message_id: &Option<String>) -> Result<ChatContext<'static>> {
+    let format = backup_format(refrigerator, backup_path)?;
     // Create a connection to the database
-    let database_path = &database_path(db_file, backup_path);
-    let conn = Connection::open(database_path).unwrap();
+    let conn = open_database(refrigerator, decrypted, backup_path)?;
     // Get the chat name and topic
-    let mut statement = conn
-        .prepare("SELECT name, topic FROM chats WHERE id = $1")
-        .unwrap();
-    let mut rows = statement.query([chat_id]).unwrap();
-    match rows.next().unwrap() {
-        Some(chat_details) => {
-            let messages = match message_id {
-                Some(id) => {
-                    // Get sequential ID from message ID
-                    let mut statement = conn
-                        .prepare("SELECT ROWID FROM messages WHERE id = $1")
-                        .unwrap();
-                    let mut rows = statement.query(&[&id]).unwrap();
-                    let row = rows.next().unwrap().unwrap();
-                    let sequential_id: u64 = row.get(0).unwrap();
-                    populate_messages(
-                        database_path,
-                        backup_path,
-                        "SELECT * FROM ({} chat = $1 AND ROWID <= $2 ORDER BY created_timestamp DESC LIMIT 50)
-                        UNION SELECT * FROM ({} chat = $1 AND ROWID > $2 ORDER BY created_timestamp LIMIT 50) ORDER BY created_timestamp",
-                        &[&chat_id, &sequential_id]
-                    )
-                },
-                None => populate_messages(
-                    database_path,
-                    backup_path,
-                    "SELECT * FROM ({} chat = $1 ORDER BY created_timestamp DESC LIMIT 100) ORDER BY created_timestamp",
-                    &[&chat_id]
-                ),
+    let (name, topic) = chat_details(&conn, chat_id)?;
+    let messages = match message_id {
+        Some(id) => {
+            // Get sequential ID from message ID
+            let mut statement = conn.prepare("SELECT ROWID FROM messages WHERE id = $1 AND chat = $2")?;
+            let mut rows = statement.query([id, chat_id])?;
+            let sequential_id: u64 = match rows.next()? {
+                Some(row) => row.get(0)?,
+                None => return Err(Error::MessageNotFound(id.to_owned())),
             };
-
-            ChatContext {
-                name: chat_details.get(0).unwrap(),
-                topic: chat_details.get(1).unwrap_or(String::new()),
-                messages,
-                selection_context: None,
-            }
+            format.populate_messages(&conn, &refrigerator.path, backup_path, MESSAGES_AROUND, &[&chat_id, &sequential_id])?
         },
-        // If the chat doesn't exist in the database
-        None => ChatContext::default(),
-    }
+        None => format.populate_messages(&conn, &refrigerator.path, backup_path, LATEST_MESSAGES, &[&chat_id])?,
+    };
+
+    Ok(ChatContext {
+        name,
+        topic,
+        messages,
+        message_id: message_id.to_owned(),
+        selection_context: None,
+    })
 }
 
-pub fn get_messages<'a>(
-    db_file: &'a Option<NamedTempFile>,
-    backup_path: &'a str,
-    chat_id: &'a str,
+pub fn get_messages(
+    refrigerator: &Refrigerator,
+    decrypted: Option<&DecryptedBackup>,
+    backup_path: &str,
+    chat_id: &str,
     sequential_id: u64,
-    position: &'a str,
-) -> Vec<Message> {
+    position: &str,
+) -> Result<Vec<Message>> {
     let condition = if position == "above" {
-        "SELECT * FROM ({} chat = $1 AND ROWID < $2 ORDER BY created_timestamp DESC LIMIT 100) ORDER BY created_timestamp"
+        MESSAGES_ABOVE
     } else if position == "below" {
-        "{} chat = $1 AND ROWID > $2 ORDER BY created_timestamp LIMIT 100"
+        MESSAGES_BELOW
     } else {
-        "SELECT * FROM ({} chat = $1 AND ROWID <= $2 ORDER BY created_timestamp DESC LIMIT 50) UNION SELECT * FROM ({} chat = $1 AND ROWID > $2 ORDER BY created_timestamp LIMIT 50) ORDER BY created_timestamp"
+        MESSAGES_AROUND
     };
-    let populate_messages = match backup_type(backup_path).as_str() {
-        "discord" => discord::populate_messages,
-        "matrix" => matrix::populate_messages,
-        "generic" => generic::populate_messages,
-        _ => return Vec::new(),
-    };
-    populate_messages(
-        &database_path(db_file, backup_path),
+    let format = backup_format(refrigerator, backup_path)?;
+    format.populate_messages(
+        &*open_database(refrigerator, decrypted, backup_path)?,
+        &refrigerator.path,
         backup_path,
         condition,
         &[&chat_id, &sequential_id]
     )
 }
 
-pub fn search<'a>(db_file: &'a Option<NamedTempFile>, backup_path: &'a str, chat_id: &'a str, query: &str, mut filters: &str) -> Vec<Message> {
-    if filters.len() == 0 {
-        filters = "TRUE";
+// Gets the messages around the first shown message at or after the local time, or around the last
+// message if there are none after it
+pub fn jump_to_date(refrigerator: &Refrigerator, decrypted: Option<&DecryptedBackup>, backup_path: &str, chat_id: &str, time: &NaiveDateTime) -> Result<DateJump> {
+    let format = backup_format(refrigerator, backup_path)?;
+    let sequential_id = {
+        let conn = open_database(refrigerator, decrypted, backup_path)?;
+        // Make sure the chat exists
+        chat_details(&conn, chat_id)?;
+        // Only messages that the format shows can be scrolled to
+        let after: Option<u64> = conn
+            .query_row(
+                &format!(
+                    "SELECT ROWID FROM messages WHERE chat = $1 AND datetime(created_timestamp) >= $2 AND ({})
+                    ORDER BY created_timestamp, ROWID LIMIT 1",
+                    format.shown_condition(),
+                ),
+                [chat_id, &filter::utc(time)],
+                |row| row.get(0),
+            )
+            .optional()?;
+        match after {
+            Some(sequential_id) => Some(sequential_id),
+            None => conn
+                .query_row(
+                    &format!(
+                        "SELECT ROWID FROM messages WHERE chat = $1 AND ({}) ORDER BY created_timestamp DESC, ROWID DESC LIMIT 1",
+                        format.shown_condition(),
+                    ),
+                    [chat_id],
+                    |row| row.get(0),
+                )
+                .optional()?,
+        }
+    };
+    let messages = match sequential_id {
+        // The day separators are added along with the messages
+        Some(sequential_id) => get_messages(refrigerator, decrypted, backup_path, chat_id, sequential_id, "around")?,
+        None => Vec::new(),
+    };
+    Ok(DateJump {sequential_id, messages})
+}
+
+// Counts the messages of the chat on each local day, or each hour of it if `hourly` is set, like
+// `["2021-03-14", 52]` or `["2021-03-14 18", 7]`
+// Days and hours without any messages are left out, and so are the messages the format doesn't show
+pub fn density(refrigerator: &Refrigerator, decrypted: Option<&DecryptedBackup>, backup_path: &str, chat_id: &str, hourly: bool) -> Result<Vec<(String, u64)>> {
+    let format = backup_format(refrigerator, backup_path)?;
+    let conn = open_database(refrigerator, decrypted, backup_path)?;
+    // Make sure the chat exists
+    chat_details(&conn, chat_id)?;
+    let period = if hourly {"%Y-%m-%d %H"} else {"%Y-%m-%d"};
+    let mut statement = conn.prepare(&format!(
+        "SELECT strftime(?2, created_timestamp, 'localtime') AS period, COUNT(*) FROM messages
+        WHERE chat = ?1 AND period IS NOT NULL AND ({}) GROUP BY period ORDER BY period",
+        format.shown_condition(),
+    ))?;
+    let rows = statement.query_map([chat_id, period], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    let mut counts = Vec::new();
+    for row in rows {
+        counts.push(row?);
+    }
+    Ok(counts)
+}
+
+// Searches the chat, or every chat in the backup if `chat_id` is None
+// The search index is built with `index_options` first if it is missing or outdated
+pub fn search(refrigerator: &Refrigerator, decrypted: Option<&DecryptedBackup>, backup_path: &str, chat_id: Option<&str>, search: &Search, index_options: index::Options) -> Result<SearchResults> {
+    let format = backup_format(refrigerator, backup_path)?;
+    let conn = open_database(refrigerator, decrypted, backup_path)?;
+    index::update(&conn, index_options)?;
+    format.search(&conn, &refrigerator.path, backup_path, chat_id, search)
+}
+
+// Searches like `search`, but goes through every page of results starting from the search's,
+// calling `each` with the hits of each page
+// The pages are read on one connection, so an index built in memory only has to be built once
+pub fn search_pages(
+    refrigerator: &Refrigerator,
+    decrypted: Option<&DecryptedBackup>,
+    backup_path: &str,
+    chat_id: Option<&str>,
+    search: &Search,
+    index_options: index::Options,
+    mut each: impl FnMut(Vec<SearchHit>) -> Result<()>,
+) -> Result<()> {
+    let format = backup_format(refrigerator, backup_path)?;
+    let conn = open_database(refrigerator, decrypted, backup_path)?;
+    index::update(&conn, index_options)?;
+    let mut page = search.page;
+    loop {
+        let results = format.search(&conn, &refrigerator.path, backup_path, chat_id, &Search {page, ..*search})?;
+        each(results.hits)?;
+        // Some results can't be rendered, so pages can have fewer hits than the limit
+        page.offset = match page.offset.checked_add(page.limit) {
+            Some(offset) if offset < results.total => offset,
+            _ => return Ok(()),
+        };
     }
-    let populate_messages = match backup_type(backup_path).as_str() {
-        "discord" => discord::populate_messages,
-        "matrix" => matrix::populate_messages,
-        "generic" => generic::populate_messages,
-        _ => return Vec::new(),
+}
+
+// Searches every chat of every backup in the refrigerator
+// Encrypted backups are only searched if they have been decrypted in the session
+pub fn global_search(refrigerator: &Refrigerator, session: &Session, search: &Search, index_options: index::Options) -> Result<GlobalSearchResults> {
+    // Any of the backups could have all the hits up to the end of the page
+    let backup_search = Search {
+        page: Page {
+            limit: search.page.offset.checked_add(search.page.limit)
+                .ok_or_else(|| Error::InvalidParameters(String::from("the page of search results is out of range")))?,
+            offset: 0,
+            ..search.page
+        },
+        ..*search
     };
-    populate_messages(
-        &database_path(db_file, backup_path),
-        backup_path,
-        &("{} chat = $1 AND id IN (SELECT id FROM message_search WHERE message_search MATCH $2 ORDER BY rank) AND ".to_owned() + filters),
-        &[&chat_id, &query]
-    )
+    let mut total = 0;
+    // The matches of every backup, which only the ones on the page are rendered from
+    let mut matches = Vec::new();
+    // The connections the matches were found on, which they're rendered on too, since the index
+    // might only exist on that connection
+    let mut databases = HashMap::new();
+    let (backup_paths, mut failed) = backup_paths(&refrigerator.path);
+    for backup_path in backup_paths {
+        match backup_matches(refrigerator, session, &backup_path, &backup_search, index_options) {
+            Ok(Some(backup)) => {
+                total += backup.total;
+                // FTS5's ranks depend on the rest of the index they come from, so they're made
+                // relative to the best match of the backup, which gets a score of 1
+                let best = backup.matches.first().map_or(0.0, |search_match| search_match.rank);
+                for search_match in backup.matches {
+                    let score = if best < 0.0 { search_match.rank / best } else { 1.0 };
+                    matches.push((backup_path.clone(), backup.name.clone(), score, search_match));
+                }
+                databases.insert(backup_path, (backup.format, backup.conn));
+            }
+            // The backup is locked
+            Ok(None) => (),
+            Err(e) => failed.push([backup_path, e.to_string()]),
+        }
+    }
+    match search.page.order {
+        SearchOrder::Relevance => matches.sort_by(|a, b| b.2.total_cmp(&a.2)),
+        SearchOrder::Newest => matches.sort_by(|a, b| b.3.timestamp.cmp(&a.3.timestamp)),
+        SearchOrder::Oldest => matches.sort_by(|a, b| a.3.timestamp.cmp(&b.3.timestamp)),
+    }
+    // Only the matches on the page are rendered, a backup at a time
+    let mut order = Vec::new();
+    let mut backups: HashMap<String, Vec<SearchMatch>> = HashMap::new();
+    for (backup_path, backup_name, _, search_match) in matches.into_iter().skip(search.page.offset).take(search.page.limit) {
+        order.push((backup_path.clone(), backup_name, search_match.sequential_id.to_string()));
+        backups.entry(backup_path).or_default().push(search_match);
+    }
+    let mut rendered = HashMap::new();
+    for (backup_path, backup_matches) in backups {
+        let (format, conn) = &databases[&backup_path];
+        match format.render_matches(conn, &refrigerator.path, &backup_path, search.query, backup_matches) {
+            Ok(hits) => rendered.extend(hits.into_iter().map(|hit| ((backup_path.clone(), hit.message.sequential_id.clone()), hit))),
+            Err(e) => failed.push([backup_path, e.to_string()]),
+        }
+    }
+    let hits = order
+        .into_iter()
+        .filter_map(|(backup_path, backup_name, sequential_id)| {
+            let hit = rendered.remove(&(backup_path.clone(), sequential_id))?;
+            Some(GlobalSearchHit { backup_path, backup_name, hit })
+        })
+        .collect();
+    Ok(GlobalSearchResults { total, hits, failed })
+}
+
+// The matches of a search in a backup, before they're rendered
+struct BackupMatches<'a> {
+    name: String,
+    // The number of matches in the backup, including the ones that weren't fetched
+    total: usize,
+    matches: Vec<SearchMatch>,
+    format: &'a dyn ArchiveFormat,
+    // The connection the matches were found on, which has the search index
+    conn: Database<'a>,
+}
+
+// Finds the matches of the search in all the chats of a backup
+// Returns None if the backup is encrypted and hasn't been decrypted
+fn backup_matches<'a>(
+    refrigerator: &'a Refrigerator,
+    session: &'a Session,
+    backup_path: &str,
+    search: &Search,
+    index_options: index::Options,
+) -> Result<Option<BackupMatches<'a>>> {
+    let (name, chats) = read_backup(refrigerator, session, backup_path)?;
+    if chats.is_none() {
+        return Ok(None);
+    }
+    let format = backup_format(refrigerator, backup_path)?;
+    let conn = open_database(refrigerator, session.decrypted(backup_path), backup_path)?;
+    index::update(&conn, index_options)?;
+    let (total, matches) = format.search_matches(&conn, None, search)?;
+    Ok(Some(BackupMatches { name, total, matches, format, conn }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A refrigerator with a Matrix backup called "backup", whose chat "!room" has the messages,
+    // given as (type, timestamp)
+    fn refrigerator(messages: &[(&str, &str)]) -> (tempfile::TempDir, Refrigerator) {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("backup")).unwrap();
+        fs::write(
+            info_path(dir.path(), "backup"),
+            r#"{"version": "0.1.0", "type": "matrix", "name": "Backup"}"#,
+        ).unwrap();
+        let conn = Connection::open(database_path(dir.path(), "backup")).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE chats (id TEXT, name TEXT, topic TEXT);
+            CREATE TABLE messages (id TEXT, chat TEXT, message_type TEXT, name TEXT, avatar TEXT, color TEXT,
+                created_timestamp TEXT, edits TEXT, reference TEXT, content TEXT, formatted_content TEXT);
+            INSERT INTO chats VALUES ('!room', 'Room', NULL);"
+        ).unwrap();
+        for (i, (message_type, timestamp)) in messages.iter().enumerate() {
+            conn.execute(
+                "INSERT INTO messages (id, chat, message_type, name, created_timestamp) VALUES ($1, '!room', $2, 'Alice', $3)",
+                [&format!("${}", i), *message_type, *timestamp],
+            ).unwrap();
+        }
+        let refrigerator = Refrigerator {
+            path: dir.path().to_owned(),
+            registry: Registry::default(),
+        };
+        (dir, refrigerator)
+    }
+
+    #[test]
+    fn backup_paths_outside_the_refrigerator() {
+        assert!(is_backup_path("backup"));
+        assert!(is_backup_path("Archive 1"));
+        for path in ["", ".", "..", "../backup", "backup/..", "backup/chats", "/backup", "backup/", "./backup"] {
+            assert!(!is_backup_path(path), "{}", path);
+        }
+        let (_dir, refrigerator) = refrigerator(&[]);
+        assert!(matches!(read_info(&refrigerator.path, "../backup"), Err(Error::InvalidBackupPath(_))));
+    }
+
+    #[test]
+    fn density_hidden_messages() {
+        let (_dir, refrigerator) = refrigerator(&[
+            ("m.text", "2021-03-10T12:00:00+00:00"),
+            ("m.room.member", "2021-03-10T12:01:00+00:00"),
+            ("m.image", "2021-03-10T12:02:00+00:00"),
+            // A day with nothing but events that aren't shown
+            ("m.room.member", "2021-03-12T12:00:00+00:00"),
+            ("m.room.name", "2021-03-12T12:01:00+00:00"),
+            ("m.text", "2021-03-14T12:00:00+00:00"),
+        ]);
+        let counts: Vec<u64> = density(&refrigerator, None, "backup", "!room", false)
+            .unwrap()
+            .into_iter()
+            .map(|(_, count)| count)
+            .collect();
+        assert_eq!(counts, [2, 1]);
+        assert_eq!(counts.iter().sum::<u64>(), message_count(&refrigerator, None, "backup", "!room").unwrap());
+    }
+
+    #[test]
+    fn global_search_plain_backup() {
+        let (_dir, refrigerator) = refrigerator(&[
+            ("m.text", "2021-03-10T12:00:00+00:00"),
+            ("m.room.member", "2021-03-10T12:01:00+00:00"),
+            ("m.text", "2021-03-10T12:02:00+00:00"),
+        ]);
+        let conn = Connection::open(database_path(&refrigerator.path, "backup")).unwrap();
+        conn.execute_batch(
+            "UPDATE messages SET content = 'Anyone want cheesecake?' WHERE id = '$0';
+            UPDATE messages SET content = 'Bob joined the cheesecake room' WHERE id = '$1';
+            UPDATE messages SET content = 'Not me' WHERE id = '$2';"
+        ).unwrap();
+        drop(conn);
+        let search = Search {
+            query: "cheesecake",
+            filters: &[],
+            page: Page { order: SearchOrder::Relevance, limit: 10, offset: 0 },
+        };
+        let index_options = index::Options { tokenizer: index::DEFAULT_TOKENIZER, save: false };
+        let results = global_search(&refrigerator, &Session::default(), &search, index_options).unwrap();
+        assert!(results.failed.is_empty(), "{:?}", results.failed);
+        assert_eq!(results.total, 1);
+        assert_eq!(results.hits.len(), 1);
+        let hit = &results.hits[0];
+        assert_eq!((hit.backup_path.as_str(), hit.backup_name.as_str()), ("backup", "Backup"));
+        assert_eq!(hit.hit.message.message_id, "$0");
+        assert_eq!(hit.hit.snippet.as_deref(), Some("Anyone want <mark>cheesecake</mark>?"));
+    }
 }
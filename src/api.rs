@@ -0,0 +1,218 @@
+// A read-only JSON API for scripts, under /api/v1
+// Backups and chats are picked with the path instead of cookies, so the session cookie is only
+// needed for reading encrypted backups that have been decrypted in it
+
+use rocket::{form, http::CookieJar, serde::json::Json, Route, State};
+use serde_json::Value;
+use cheesecake::{
+    actions::{self, BackupSummary, Chat, GlobalSearchHit, Refrigerator, SearchHit, User},
+    error::Error,
+    filter,
+    format::{Page, Search},
+};
+use super::{response::ErrorResponse, sessions::Sessions, BackupDir, OrderField, Settings, MAX_LIMIT, MAX_OFFSET};
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        get_backups,
+        get_chats,
+        get_chat,
+        get_users,
+        get_messages,
+        get_message,
+        get_search,
+        get_global_search,
+    ]
+}
+
+// A page of a list, with the cursor of the next page if there is one
+// The next page is requested by passing the cursor as the `cursor` query parameter
+#[derive(Serialize)]
+pub struct Listing<T> {
+    items: Vec<T>,
+    next: Option<u64>,
+    // The number of items on all the pages, for lists where it's known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct GlobalListing {
+    #[serde(flatten)]
+    listing: Listing<GlobalSearchHit>,
+    // Backups that couldn't be searched, with the reason
+    failed: Vec<[String; 2]>,
+}
+
+#[derive(Serialize)]
+pub struct Backups {
+    backups: Vec<BackupSummary>,
+    // Backups that couldn't be read, with the reason
+    failed: Vec<[String; 2]>,
+}
+
+#[derive(FromForm)]
+pub struct Pagination {
+    #[field(default = 50, validate = range(1..=MAX_LIMIT as isize))]
+    limit: usize,
+    cursor: Option<u64>,
+}
+
+#[derive(FromForm)]
+pub struct SearchQuery {
+    q: String,
+    filters: Option<String>,
+    order: OrderField,
+    #[field(default = 50, validate = range(1..=MAX_LIMIT as isize))]
+    limit: usize,
+    // The offset of the page
+    #[field(default = 0, validate = range(0..=MAX_OFFSET as isize))]
+    cursor: usize,
+}
+
+impl SearchQuery {
+    fn search<'a>(&'a self, filters: &'a [filter::Filter]) -> Search<'a> {
+        Search {
+            query: &self.q,
+            filters,
+            page: Page {
+                order: self.order.0,
+                limit: self.limit,
+                offset: self.cursor,
+            },
+        }
+    }
+}
+
+// Parameters that can't be parsed are reported, instead of the request not matching the route
+fn parameters<T>(form: form::Result<'_, T>) -> Result<T, Error> {
+    form.map_err(|errors| {
+        let errors: Vec<_> = errors
+            .iter()
+            .map(|error| match &error.name {
+                Some(name) => format!("`{}` {}", name, error.kind),
+                None => error.kind.to_string(),
+            })
+            .collect();
+        Error::InvalidParameters(errors.join(", "))
+    })
+}
+
+// Drops the item that was fetched beyond the limit to find out if there's a next page, and returns
+// the cursor of the next page, which is the ID of the last item on this one
+fn next_cursor<T>(items: &mut Vec<T>, limit: usize, id: impl Fn(&T) -> Option<u64>) -> Option<u64> {
+    if items.len() <= limit {
+        return None;
+    }
+    items.truncate(limit);
+    items.last().and_then(id)
+}
+
+// The offset of the next page of search results
+// Hits that can't be rendered are left out of the pages, so they're counted by the limit instead
+fn search_page(search: &Search, total: usize) -> Result<Option<u64>, Error> {
+    let end = search.page.offset.checked_add(search.page.limit)
+        .ok_or_else(|| Error::InvalidParameters(String::from("`cursor` is out of range")))?;
+    Ok(if end < total {Some(end as u64)} else {None})
+}
+
+#[get("/backups")]
+fn get_backups(refrigerator: &State<Refrigerator>, sessions: &State<Sessions>, cookies: &CookieJar<'_>) -> Json<Backups> {
+    let (backups, failed) = actions::backups(refrigerator, &sessions.get(cookies).lock().unwrap());
+    Json(Backups {backups, failed})
+}
+
+#[get("/backups/<backup>/chats")]
+fn get_chats(refrigerator: &State<Refrigerator>, sessions: &State<Sessions>, cookies: &CookieJar<'_>, backup: BackupDir<'_>) -> Result<Json<Vec<Chat>>, ErrorResponse> {
+    let BackupDir(backup) = backup;
+    let session = sessions.get(cookies);
+    let session = session.lock().unwrap();
+    Ok(Json(actions::chats(refrigerator, session.decrypted(backup), backup)?))
+}
+
+#[get("/backups/<backup>/chats/<chat>")]
+fn get_chat(refrigerator: &State<Refrigerator>, sessions: &State<Sessions>, cookies: &CookieJar<'_>, backup: BackupDir<'_>, chat: &str) -> Result<Json<Chat>, ErrorResponse> {
+    let BackupDir(backup) = backup;
+    let session = sessions.get(cookies);
+    let session = session.lock().unwrap();
+    Ok(Json(actions::find_chat(refrigerator, session.decrypted(backup), backup, chat)?))
+}
+
+// The people who sent messages in the backup, or only in the chat if one is given
+#[get("/backups/<backup>/users?<chat>")]
+fn get_users(refrigerator: &State<Refrigerator>, sessions: &State<Sessions>, cookies: &CookieJar<'_>, backup: BackupDir<'_>, chat: Option<&str>) -> Result<Json<Vec<User>>, ErrorResponse> {
+    let BackupDir(backup) = backup;
+    let session = sessions.get(cookies);
+    let session = session.lock().unwrap();
+    Ok(Json(actions::users(refrigerator, session.decrypted(backup), backup, chat)?))
+}
+
+// The messages of the chat from the oldest one, as the rows of the messages table, or the way the
+// reader shows them with `rendered`
+#[get("/backups/<backup>/chats/<chat>/messages?<rendered>&<page..>")]
+fn get_messages(refrigerator: &State<Refrigerator>, sessions: &State<Sessions>, cookies: &CookieJar<'_>, backup: BackupDir<'_>, chat: &str, rendered: bool, page: form::Result<'_, Pagination>) -> Result<Json<Listing<Value>>, ErrorResponse> {
+    let BackupDir(backup) = backup;
+    let page = parameters(page)?;
+    let session = sessions.get(cookies);
+    let session = session.lock().unwrap();
+    let decrypted = session.decrypted(backup);
+    let (items, next) = if rendered {
+        // Some rows aren't shown, so the page can have fewer messages than the limit
+        let (messages, next) = actions::messages_after(refrigerator, decrypted, backup, chat, page.cursor, page.limit)?;
+        (messages.iter().map(serde_json::to_value).collect::<Result<_, _>>()?, next)
+    } else {
+        let mut items = actions::raw_messages_after(refrigerator, decrypted, backup, chat, page.cursor, page.limit + 1)?;
+        let next = next_cursor(&mut items, page.limit, |message| message["sequential_id"].as_u64());
+        (items, next)
+    };
+    Ok(Json(Listing {items, next, total: None}))
+}
+
+#[get("/backups/<backup>/chats/<chat>/messages/<message>?<rendered>")]
+fn get_message(refrigerator: &State<Refrigerator>, sessions: &State<Sessions>, cookies: &CookieJar<'_>, backup: BackupDir<'_>, chat: &str, message: &str, rendered: bool) -> Result<Json<Value>, ErrorResponse> {
+    let BackupDir(backup) = backup;
+    let session = sessions.get(cookies);
+    let session = session.lock().unwrap();
+    let decrypted = session.decrypted(backup);
+    if rendered {
+        Ok(Json(serde_json::to_value(actions::message(refrigerator, decrypted, backup, chat, message)?)?))
+    } else {
+        Ok(Json(actions::raw_message(refrigerator, decrypted, backup, chat, message)?))
+    }
+}
+
+// Searches the backup, or only the chat if one is given
+#[get("/backups/<backup>/search?<chat>&<query..>")]
+fn get_search(refrigerator: &State<Refrigerator>, settings: &State<Settings>, sessions: &State<Sessions>, cookies: &CookieJar<'_>, backup: BackupDir<'_>, chat: Option<&str>, query: form::Result<'_, SearchQuery>) -> Result<Json<Listing<SearchHit>>, ErrorResponse> {
+    let BackupDir(backup) = backup;
+    let query = parameters(query)?;
+    let filters = filter::parse(query.filters.as_deref().unwrap_or_default(), settings.allow_sql_filters)?;
+    let search = query.search(&filters);
+    let session = sessions.get(cookies);
+    let session = session.lock().unwrap();
+    let results = actions::search(refrigerator, session.decrypted(backup), backup, chat, &search, settings.index_options())?;
+    Ok(Json(Listing {
+        next: search_page(&search, results.total)?,
+        items: results.hits,
+        total: Some(results.total),
+    }))
+}
+
+// Searches every backup that can be read in the session
+#[get("/search?<query..>")]
+fn get_global_search(refrigerator: &State<Refrigerator>, settings: &State<Settings>, sessions: &State<Sessions>, cookies: &CookieJar<'_>, query: form::Result<'_, SearchQuery>) -> Result<Json<GlobalListing>, ErrorResponse> {
+    let query = parameters(query)?;
+    let filters = filter::parse(query.filters.as_deref().unwrap_or_default(), settings.allow_sql_filters)?;
+    let search = query.search(&filters);
+    let session = sessions.get(cookies);
+    let session = session.lock().unwrap();
+    let results = actions::global_search(refrigerator, &session, &search, settings.index_options())?;
+    Ok(Json(GlobalListing {
+        listing: Listing {
+            next: search_page(&search, results.total)?,
+            items: results.hits,
+            total: Some(results.total),
+        },
+        failed: results.failed,
+    }))
+}
@@ -0,0 +1,366 @@
+use zeroize::Zeroizing;
+use std::{collections::{HashMap, HashSet}, fs::File, io::{self, BufWriter, Write}, ops::RangeInclusive, path::Path};
+use cheesecake::actions::{self, database_path, Refrigerator, SearchHit};
+use cheesecake::decrypted::DecryptedBackup;
+use cheesecake::encryption::{self, Kdf, Progress};
+use cheesecake::error::{Error, Result};
+use cheesecake::filter;
+use cheesecake::format::{Page, Search, SearchOrder};
+use cheesecake::session::Session;
+use super::{export::{self, plain_text, Format, TimeRange, Writer}, Settings};
+
+// The arguments of a command, with options like `--limit 10` and flags like `--json` taken out
+#[derive(Default)]
+pub struct Arguments<'a> {
+    pub positional: Vec<&'a str>,
+    options: HashMap<&'a str, &'a str>,
+}
+
+impl<'a> Arguments<'a> {
+    // Returns None if the command doesn't take an option, one is missing its value, or there are too
+    // many or too few other arguments
+    pub fn parse(args: &[&'a str], positional: RangeInclusive<usize>, options: &[&str], flags: &[&str]) -> Option<Self> {
+        let mut arguments = Arguments::default();
+        let mut args = args.iter();
+        while let Some(&arg) = args.next() {
+            if flags.contains(&arg) {
+                arguments.options.insert(arg, "");
+            } else if options.contains(&arg) {
+                arguments.options.insert(arg, args.next()?);
+            } else if arg.starts_with("--") {
+                return None;
+            } else {
+                arguments.positional.push(arg);
+            }
+        }
+        positional.contains(&arguments.positional.len()).then_some(arguments)
+    }
+
+    pub fn option(&self, name: &str) -> Option<&'a str> {
+        self.options.get(name).copied()
+    }
+
+    pub fn flag(&self, name: &str) -> bool {
+        self.options.contains_key(name)
+    }
+}
+
+// Encrypts a plaintext backup with a new password
+pub fn encrypt(refrigerator: &Path, backup_path: &str, kdf: Option<&str>) -> Result<()> {
+    let kdf = parse_kdf(kdf)?;
+    // Check before asking for a password
+    if encryption::is_encrypted(refrigerator, backup_path)? {
+        return Err(Error::AlreadyEncrypted);
+    }
+    let password = new_password("Password: ")?;
+    encryption::encrypt(refrigerator, backup_path, &password, &kdf)?;
+    println!("Encrypted `{}`", backup_path);
+    Ok(())
+}
+
+// Changes the password of an encrypted backup
+// The backup is re-encrypted with the given KDF, or the default one
+pub fn rekey(refrigerator: &Path, backup_path: &str, kdf: Option<&str>) -> Result<()> {
+    let kdf = parse_kdf(kdf)?;
+    let old_key = encryption::backup_key(refrigerator, backup_path, &prompt_password("Current password: ")?)?;
+    // Opening the database decrypts its first segment, which checks the current password before
+    // asking for a new one
+    let mut plaintext = old_key.open(&database_path(refrigerator, backup_path))?;
+    let new_password = new_password("New password: ")?;
+    let size = plaintext.size();
+    encryption::write_encrypted(
+        refrigerator,
+        backup_path,
+        &mut plaintext,
+        size,
+        &new_password,
+        &kdf,
+        Some(&old_key),
+    )?;
+    println!("Changed the password of `{}`", backup_path);
+    Ok(())
+}
+
+// Writes a decrypted copy of an encrypted backup's database to `output`
+pub fn decrypt(refrigerator: &Path, backup_path: &str, output: &str) -> Result<()> {
+    if !encryption::is_encrypted(refrigerator, backup_path)? {
+        return Err(Error::NotEncrypted);
+    }
+    let key = encryption::backup_key(refrigerator, backup_path, &prompt_password("Password: ")?)?;
+    encryption::decrypt_to_file(refrigerator, backup_path, &key, Path::new(output))?;
+    println!("Wrote the decrypted database of `{}` to `{}`", backup_path, output);
+    Ok(())
+}
+
+// Encrypts the assets of an encrypted backup
+pub fn encrypt_assets(refrigerator: &Path, backup_path: &str) -> Result<()> {
+    if !encryption::is_encrypted(refrigerator, backup_path)? {
+        return Err(Error::NotEncrypted);
+    }
+    let key = encryption::backup_key(refrigerator, backup_path, &prompt_password("Password: ")?)?;
+    // Check the password before encrypting anything with the key
+    encryption::check_key(refrigerator, backup_path, &key)?;
+    let count = encryption::encrypt_assets(refrigerator, backup_path, &key)?;
+    println!("Encrypted {} asset{} of `{}`", count, if count == 1 {""} else {"s"}, backup_path);
+    Ok(())
+}
+
+// Prints the backups in the refrigerator, or the chats of a backup
+pub fn list(refrigerator: &Refrigerator, args: &Arguments, settings: &Settings) -> Result<()> {
+    match args.positional[..] {
+        [backup_path] => {
+            let decrypted = unlock(refrigerator, backup_path, settings)?;
+            let chats = actions::chats(refrigerator, decrypted.as_ref(), backup_path)?;
+            if args.flag("--json") {
+                println!("{}", serde_json::to_string(&chats)?);
+            } else {
+                for chat in chats {
+                    println!("{}\t{}", chat.id, chat.name);
+                }
+            }
+        }
+        _ => {
+            // Nothing has been decrypted, so encrypted backups are listed as locked
+            let (backups, failed) = actions::backups(refrigerator, &Session::default());
+            if args.flag("--json") {
+                println!("{}", serde_json::json!({ "backups": backups, "failed": failed }));
+            } else {
+                for backup in backups {
+                    println!("{}\t{}\t{}{}", backup.path, backup.name, backup.format, if backup.encrypted {"\tencrypted"} else {""});
+                }
+                for [path, reason] in failed {
+                    eprintln!("Couldn't read `{}`: {}", path, reason);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Prints the results of searching a backup or chat, or every unencrypted backup if none is given
+pub fn search(refrigerator: &Refrigerator, args: &Arguments, settings: &Settings) -> Result<()> {
+    let filters = filter::parse(args.option("--filters").unwrap_or_default(), settings.allow_sql_filters)?;
+    let order = match args.option("--order") {
+        Some(name) => SearchOrder::from_name(name)
+            .ok_or_else(|| Error::InvalidParameters(String::from("`--order` expected relevance, newest or oldest")))?,
+        None => SearchOrder::Relevance,
+    };
+    let limit = match args.option("--limit") {
+        Some(limit) => limit
+            .parse()
+            .map_err(|_| Error::InvalidParameters(format!("`--limit` expected a number, not `{}`", limit)))?,
+        None => 20,
+    };
+    let search = Search {
+        query: args.positional[0],
+        filters: &filters,
+        page: Page {order, limit, offset: 0},
+    };
+    let json = args.flag("--json");
+    let (total, shown) = match (args.option("--backup"), args.option("--chat")) {
+        (Some(backup_path), chat_id) => {
+            let decrypted = unlock(refrigerator, backup_path, settings)?;
+            let results = actions::search(refrigerator, decrypted.as_ref(), backup_path, chat_id, &search, settings.index_options())?;
+            if json {
+                println!("{}", serde_json::to_string(&results)?);
+            } else {
+                for hit in &results.hits {
+                    print_hit(&hit.chat_name, hit);
+                }
+            }
+            (results.total, results.hits.len())
+        }
+        (None, Some(_)) => return Err(Error::InvalidParameters(String::from("`--chat` needs `--backup`"))),
+        (None, None) => {
+            let results = actions::global_search(refrigerator, &Session::default(), &search, settings.index_options())?;
+            if json {
+                println!("{}", serde_json::to_string(&results)?);
+            } else {
+                for hit in &results.hits {
+                    print_hit(&format!("{} / {}", hit.backup_name, hit.hit.chat_name), &hit.hit);
+                }
+            }
+            for [path, reason] in &results.failed {
+                eprintln!("Couldn't search `{}`: {}", path, reason);
+            }
+            (results.total, results.hits.len())
+        }
+    };
+    if !json {
+        println!("{} of {} result{}", shown, total, if total == 1 {""} else {"s"});
+    }
+    Ok(())
+}
+
+fn print_hit(place: &str, hit: &SearchHit) {
+    let text = match &hit.snippet {
+        Some(snippet) => plain_text(snippet),
+        None => plain_text(&hit.message.content),
+    };
+    println!("[{}] {}: {}: {}", hit.message.created_timestamp, place, hit.message.name, text.replace('\n', " "));
+}
+
+// Exports a chat, part of one or search results, or every chat of the backup to HTML
+pub fn export(refrigerator: &Refrigerator, args: &Arguments, settings: &Settings) -> Result<()> {
+    let backup_path = args.positional[0];
+    let chat_id = args.positional.get(1).copied();
+    let output = args.option("--output");
+    let query = args.option("--search");
+    let range = TimeRange {
+        since: args.option("--since").map(filter::parse_time).transpose()?,
+        until: args.option("--until").map(filter::parse_time).transpose()?,
+    };
+    let filters = filter::parse(args.option("--filters").unwrap_or_default(), settings.allow_sql_filters)?;
+    // None is HTML
+    let format = match args.option("--format").unwrap_or("json") {
+        "html" => None,
+        name => Some(Format::from_name(name).ok_or_else(|| {
+            Error::InvalidParameters(String::from("`--format` expected json, csv, transcript, markdown or html"))
+        })?),
+    };
+    let problem = match format {
+        None if output.is_none() => Some("`--format html` needs `--output <directory>`"),
+        None if query.is_some() || !range.is_whole() => Some("`--format html` only exports whole chats"),
+        // The attachments are copied next to the document
+        Some(Format::Markdown) if output.is_none() => Some("`--format markdown` needs `--output <file>`"),
+        Some(_) if chat_id.is_none() && query.is_none() => Some("exporting every chat needs `--format html`"),
+        _ if query.is_none() && !filters.is_empty() => Some("`--filters` needs `--search`"),
+        _ => None,
+    };
+    if let Some(problem) = problem {
+        return Err(Error::InvalidParameters(String::from(problem)));
+    }
+    let mut session = Session::default();
+    if let Some(decrypted) = unlock(refrigerator, backup_path, settings)? {
+        session.insert(backup_path, decrypted);
+    }
+    let decrypted = session.decrypted(backup_path);
+    // Check that the chats exist before creating any files
+    let chats = match chat_id {
+        Some(chat_id) => vec![actions::find_chat(refrigerator, decrypted, backup_path, chat_id)?],
+        None => actions::chats(refrigerator, decrypted, backup_path)?,
+    };
+    if chats.is_empty() {
+        return Err(Error::InvalidParameters(format!("`{}` has no chats to export", backup_path)));
+    }
+    let count = match format {
+        None => export::html(refrigerator, &session, backup_path, &chats, Path::new(output.unwrap_or_default()))?,
+        Some(format) => {
+            let mut destination: Box<dyn Write> = match output {
+                Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+                None => Box::new(BufWriter::new(io::stdout().lock())),
+            };
+            let mut attachments = HashSet::new();
+            let writer = Writer::new(format, &range, (format == Format::Markdown).then_some(&mut attachments), &mut destination)?;
+            let count = match query {
+                Some(query) => {
+                    // The page is decided by the export
+                    let search = Search {query, filters: &filters, page: Page {order: SearchOrder::Oldest, limit: 0, offset: 0}};
+                    export::search(refrigerator, decrypted, backup_path, chat_id, &search, settings.index_options(), writer)?
+                }
+                None => export::chat(refrigerator, decrypted, backup_path, &chats[0], writer)?,
+            };
+            if let Some(path) = output.filter(|_| format == Format::Markdown) {
+                export::copy_attachments(refrigerator, &session, backup_path, &attachments, Path::new(path).parent().unwrap_or(Path::new("")))?;
+            }
+            count
+        }
+    };
+    if let Some(path) = output {
+        println!("Exported {} message{} to `{}`", count, if count == 1 {""} else {"s"}, path);
+    }
+    Ok(())
+}
+
+// Asks for the password of the backup and decrypts it if it's encrypted
+fn unlock(refrigerator: &Refrigerator, backup_path: &str, settings: &Settings) -> Result<Option<DecryptedBackup>> {
+    if !encryption::is_encrypted(&refrigerator.path, backup_path)? {
+        return Ok(None);
+    }
+    let password = prompt_password("Password: ")?;
+    let (decrypted, _) = actions::decrypt(refrigerator, backup_path, &password, settings.decrypt_in_memory, &Progress::default())?;
+    Ok(Some(decrypted))
+}
+
+fn parse_kdf(name: Option<&str>) -> Result<Kdf> {
+    match name {
+        Some(name) => Kdf::from_name(name).ok_or_else(|| Error::UnknownKdf(name.to_owned())),
+        None => Ok(Kdf::default()),
+    }
+}
+
+// Asks for a password twice, until both match
+fn new_password(prompt: &str) -> Result<Zeroizing<String>> {
+    loop {
+        let password = prompt_password(prompt)?;
+        if password.is_empty() {
+            println!("The password can't be empty");
+        } else if password == prompt_password("Repeat the password: ")? {
+            return Ok(password);
+        } else {
+            println!("The passwords didn't match");
+        }
+    }
+}
+
+// Reads a password from the terminal without echoing it
+fn prompt_password(prompt: &str) -> Result<Zeroizing<String>> {
+    // The prompt goes to stderr so that it isn't mixed into output that's piped somewhere
+    eprint!("{}", prompt);
+    io::stderr().flush()?;
+    let echo = HiddenInput::new();
+    let mut password = Zeroizing::new(String::new());
+    if io::stdin().read_line(&mut password)? == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "no password was entered").into());
+    }
+    if echo.is_some() {
+        // The newline the user typed wasn't echoed either
+        eprintln!();
+    }
+    let length = password.trim_end_matches(['\r', '\n']).len();
+    password.truncate(length);
+    Ok(password)
+}
+
+// Turns off terminal echo until it is dropped
+#[cfg(unix)]
+struct HiddenInput(libc::termios);
+
+#[cfg(unix)]
+impl HiddenInput {
+    // Returns None if stdin isn't a terminal
+    fn new() -> Option<Self> {
+        unsafe {
+            let mut termios = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) != 0 {
+                return None;
+            }
+            let original = termios;
+            termios.c_lflag &= !libc::ECHO;
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) != 0 {
+                return None;
+            }
+            Some(HiddenInput(original))
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for HiddenInput {
+    fn drop(&mut self) {
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.0);
+        }
+    }
+}
+
+// Passwords are echoed on other platforms
+#[cfg(not(unix))]
+struct HiddenInput;
+
+#[cfg(not(unix))]
+impl HiddenInput {
+    fn new() -> Option<Self> {
+        None
+    }
+}
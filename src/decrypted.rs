@@ -0,0 +1,99 @@
+use rusqlite::{ffi, Connection};
+use tempfile::NamedTempFile;
+use zeroize::Zeroizing;
+use std::{fs, io::{BufWriter, Write}, ops::Deref, path::Path};
+use super::encryption::{Key, Progress};
+use super::error::Result;
+
+const MAIN: &[u8] = b"main\0";
+
+// A decrypted copy of an encrypted backup
+pub struct DecryptedBackup {
+    database: DecryptedDatabase,
+    // For decrypting the assets of the backup
+    pub key: Key,
+}
+
+enum DecryptedDatabase {
+    // The decrypted database is in a temporary file, which gets deleted when this is dropped
+    File(NamedTempFile),
+    // The decrypted database only exists in memory
+    Memory(MemoryDatabase),
+}
+
+impl DecryptedBackup {
+    // Decrypts the database of the backup at `path`, reporting how far along it is to `progress`
+    pub fn decrypt(path: &Path, key: Key, in_memory: bool, progress: &Progress) -> Result<Self> {
+        let database = if in_memory {
+            // The ciphertext is always longer than the plaintext, so the buffer never has to grow
+            // (which would leave a copy of the plaintext behind)
+            let mut plaintext = Zeroizing::new(Vec::with_capacity(fs::metadata(path)?.len() as usize));
+            key.decrypt(path, &mut *plaintext, progress)?;
+            DecryptedDatabase::Memory(MemoryDatabase::new(plaintext)?)
+        } else {
+            let mut file = NamedTempFile::new()?;
+            let mut writer = BufWriter::new(&mut file);
+            key.decrypt(path, &mut writer, progress)?;
+            writer.flush()?;
+            drop(writer);
+            DecryptedDatabase::File(file)
+        };
+        Ok(DecryptedBackup { database, key })
+    }
+    pub fn open(&self) -> Result<Database<'_>> {
+        Ok(match &self.database {
+            DecryptedDatabase::File(file) => Database::Owned(Connection::open(file.path())?),
+            DecryptedDatabase::Memory(memory) => Database::Borrowed(&memory.conn),
+        })
+    }
+}
+
+// A connection to a backup's database
+// In-memory databases are only reachable through the connection that created them, so that
+// connection is lent out instead of opening a new one
+pub enum Database<'a> {
+    Owned(Connection),
+    Borrowed(&'a Connection),
+}
+
+impl Deref for Database<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        match self {
+            Self::Owned(conn) => conn,
+            Self::Borrowed(conn) => conn,
+        }
+    }
+}
+
+// An SQLite database that lives entirely in memory, loaded with sqlite3_deserialize
+// SQLite reads the buffer in place, so the plaintext is never copied
+struct MemoryDatabase {
+    // The connection is declared first so that it gets closed before the buffer is wiped
+    conn: Connection,
+    _buffer: Zeroizing<Vec<u8>>,
+}
+
+impl MemoryDatabase {
+    fn new(mut buffer: Zeroizing<Vec<u8>>) -> Result<Self> {
+        let conn = Connection::open_in_memory()?;
+        let size = buffer.len() as i64;
+        // The buffer has to stay where it is until the connection is closed, which it does
+        // because it is never modified
+        let result = unsafe {
+            ffi::sqlite3_deserialize(
+                conn.handle(),
+                MAIN.as_ptr() as *const _,
+                buffer.as_mut_ptr(),
+                size,
+                size,
+                ffi::SQLITE_DESERIALIZE_READONLY as u32,
+            )
+        };
+        if result != ffi::SQLITE_OK {
+            return Err(rusqlite::Error::SqliteFailure(ffi::Error::new(result), None).into());
+        }
+        Ok(MemoryDatabase { conn, _buffer: buffer })
+    }
+}
@@ -0,0 +1,664 @@
+use glob::glob;
+use tempfile::NamedTempFile;
+use zeroize::Zeroizing;
+use std::{
+    ffi::OsStr,
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Write},
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+use super::actions::{database_path, info_path, read_info};
+use super::error::{Error, Result};
+use super::format::AssetType::*;
+
+// The most memory the KDFs are allowed to use, so that a bad info.json can't use up all of it
+const KDF_MAX_MEMORY: u64 = 1 << 30;
+// The parameters that the KDFs take longer with are kept to these ranges, so that a bad info.json
+// can't keep a thread busy for hours either. The minimums keep out parameters too weak to mean
+// anything, along with ones the KDFs don't accept at all
+const PBKDF2_ITERATIONS: RangeInclusive<u32> = 1_000..=10_000_000;
+const ARGON2_ITERATIONS: RangeInclusive<u32> = 1..=16;
+const ARGON2_PARALLELISM: RangeInclusive<u32> = 1..=16;
+const SCRYPT_LOG_N: RangeInclusive<u8> = 10..=24;
+const SCRYPT_R: RangeInclusive<u32> = 1..=32;
+const SCRYPT_P: RangeInclusive<u32> = 1..=16;
+
+// How the key of an encrypted backup is derived from its password
+// It's stored as `kdf` in info.json, except for PBKDF2, which uses the top level `iterations` key
+// for compatibility with backups from before `kdf` existed
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "name", rename_all = "lowercase")]
+pub enum Kdf {
+    // PBKDF2-SHA256
+    Pbkdf2 { iterations: u32 },
+    // Memory is in KiB
+    Argon2id { memory: u32, iterations: u32, parallelism: u32 },
+    // The cost parameter N is 2^log_n
+    Scrypt { log_n: u8, r: u32, p: u32 },
+}
+
+impl Default for Kdf {
+    fn default() -> Self {
+        Kdf::from_name("argon2id").unwrap()
+    }
+}
+
+impl Kdf {
+    // Gets a KDF with the parameters cheesecake uses for new backups
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "pbkdf2" => Some(Kdf::Pbkdf2 { iterations: 390_000 }),
+            "argon2id" => Some(Kdf::Argon2id { memory: 64 * 1024, iterations: 3, parallelism: 4 }),
+            "scrypt" => Some(Kdf::Scrypt { log_n: 17, r: 8, p: 1 }),
+            _ => None,
+        }
+    }
+
+    // Reads the KDF of an encrypted backup from its info.json
+    fn from_info(info: &serde_json::Value) -> Result<Self> {
+        if info["kdf"].is_null() {
+            let iterations = info["iterations"].as_u64().ok_or(Error::InvalidInfo("iterations"))?;
+            let iterations = u32::try_from(iterations).map_err(|_| Error::InvalidInfo("iterations"))?;
+            let kdf = Kdf::Pbkdf2 { iterations };
+            kdf.check().map_err(|_| Error::InvalidInfo("iterations"))?;
+            return Ok(kdf);
+        }
+        let kdf: Kdf = serde_json::from_value(info["kdf"].clone()).map_err(|_| Error::InvalidInfo("kdf"))?;
+        kdf.check()?;
+        Ok(kdf)
+    }
+
+    // Makes sure the parameters are within the limits
+    fn check(&self) -> Result<()> {
+        let within_limits = match *self {
+            Kdf::Pbkdf2 { iterations } => PBKDF2_ITERATIONS.contains(&iterations),
+            Kdf::Argon2id { memory, iterations, parallelism } => {
+                memory as u64 <= KDF_MAX_MEMORY / 1024
+                    && ARGON2_ITERATIONS.contains(&iterations)
+                    && ARGON2_PARALLELISM.contains(&parallelism)
+            }
+            Kdf::Scrypt { log_n, r, p } => {
+                SCRYPT_LOG_N.contains(&log_n) && SCRYPT_R.contains(&r) && SCRYPT_P.contains(&p)
+            }
+        };
+        if within_limits {Ok(())} else {Err(Error::InvalidInfo("kdf"))}
+    }
+
+    // Stores the KDF in info.json
+    fn write_info(&self, info: &mut serde_json::Value) -> Result<()> {
+        let info = info.as_object_mut().ok_or(Error::InvalidInfo("info.json"))?;
+        match self {
+            Kdf::Pbkdf2 { iterations } => {
+                info.remove("kdf");
+                info.insert(String::from("iterations"), (*iterations).into());
+            }
+            _ => {
+                info.remove("iterations");
+                info.insert(String::from("kdf"), serde_json::to_value(self)?);
+            }
+        }
+        Ok(())
+    }
+
+    fn derive(&self, password: &str, salt: &[u8], key: &mut [u8]) -> Result<()> {
+        self.check()?;
+        match *self {
+            Kdf::Pbkdf2 { iterations } => openssl::pkcs5::pbkdf2_hmac(
+                password.as_bytes(),
+                salt,
+                iterations as usize,
+                openssl::hash::MessageDigest::sha256(),
+                key,
+            ).map_err(io::Error::other)?,
+            Kdf::Argon2id { memory, iterations, parallelism } => {
+                let params = argon2::Params::new(memory, iterations, parallelism, Some(key.len()))
+                    .map_err(|_| Error::InvalidInfo("kdf"))?;
+                argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+                    .hash_password_into(password.as_bytes(), salt, key)
+                    .map_err(|_| Error::InvalidInfo("kdf"))?;
+            }
+            Kdf::Scrypt { log_n, r, p } => openssl::pkcs5::scrypt(
+                password.as_bytes(),
+                salt,
+                1u64.checked_shl(log_n as u32).ok_or(Error::InvalidInfo("kdf"))?,
+                r as u64,
+                p as u64,
+                KDF_MAX_MEMORY,
+                key,
+            ).map_err(|_| Error::InvalidInfo("kdf"))?,
+        }
+        Ok(())
+    }
+}
+
+// Files encrypted by cheesecake are split into segments, so that they can be decrypted without
+// reading all of them into memory
+// The file starts with this, followed by the size of the plaintext as a big-endian u64
+// Each segment is a big-endian u32 length followed by a fernet token, whose plaintext is the index
+// of the segment as a big-endian u64, a byte that is 1 for the last segment, and then the data
+// The index and the last segment flag stop segments from being reordered or cut off unnoticed
+// Files that don't start with it are a single fernet token, like the recipes script writes them
+const CHUNKED_MAGIC: &[u8; 21] = b"CHEESECAKE-CHUNKED-1\n";
+// The amount of plaintext in each segment
+const SEGMENT_SIZE: usize = 1024 * 1024;
+// The index and the last segment flag
+const SEGMENT_HEADER_SIZE: usize = 9;
+
+// How far along decrypting a file is, which can be read from another thread
+#[derive(Default)]
+pub struct Progress {
+    done: AtomicU64,
+    total: AtomicU64,
+}
+
+impl Progress {
+    // Returns the number of bytes that have been decrypted, and the total number of bytes
+    pub fn get(&self) -> [u64; 2] {
+        [self.done.load(Ordering::Relaxed), self.total.load(Ordering::Relaxed)]
+    }
+}
+
+// The fernet key of an encrypted backup, which the database and any encrypted assets are
+// encrypted with
+pub struct Key(Zeroizing<String>);
+
+impl Key {
+    // Derives the key from the password
+    fn derive(password: &str, salt: &[u8], kdf: &Kdf) -> Result<Self> {
+        let mut key = Zeroizing::new([0; 32]);
+        kdf.derive(password, salt, &mut *key)?;
+        // Fernet keys are URL-safe base64 encoded
+        Ok(Key(Zeroizing::new(base64::encode_config(*key, base64::URL_SAFE))))
+    }
+
+    fn fernet(&self) -> fernet::Fernet {
+        // The key is always 32 bytes long, so this can't fail
+        fernet::Fernet::new(&self.0).unwrap()
+    }
+
+    // Encrypts `size` bytes read from `plaintext` into `output`, in segments
+    fn encrypt(&self, plaintext: &mut dyn Read, size: u64, output: &mut dyn Write) -> Result<()> {
+        let fernet = self.fernet();
+        output.write_all(CHUNKED_MAGIC)?;
+        output.write_all(&size.to_be_bytes())?;
+        let mut segment = Zeroizing::new(vec![0; SEGMENT_HEADER_SIZE + SEGMENT_SIZE]);
+        let mut written = 0;
+        for index in 0u64.. {
+            let length = (size - written).min(SEGMENT_SIZE as u64) as usize;
+            let last = written + length as u64 == size;
+            segment[..8].copy_from_slice(&index.to_be_bytes());
+            segment[8] = last as u8;
+            plaintext.read_exact(&mut segment[SEGMENT_HEADER_SIZE..SEGMENT_HEADER_SIZE + length]).map_err(read_error)?;
+            let token = fernet.encrypt(&segment[..SEGMENT_HEADER_SIZE + length]);
+            output.write_all(&(token.len() as u32).to_be_bytes())?;
+            output.write_all(token.as_bytes())?;
+            written += length as u64;
+            if last {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    // Decrypts a file that was encrypted with this key into `output`
+    // Any key can be derived from a password, so a key that can't decrypt the file means the
+    // password was wrong
+    pub fn decrypt(&self, path: &Path, output: &mut dyn Write, progress: &Progress) -> Result<()> {
+        let mut plaintext = self.open(path)?;
+        progress.total.store(plaintext.size(), Ordering::Relaxed);
+        let mut written = 0;
+        loop {
+            let segment = plaintext.unread();
+            output.write_all(segment)?;
+            written += segment.len() as u64;
+            progress.done.store(written, Ordering::Relaxed);
+            if !plaintext.next_segment()? {
+                return Ok(());
+            }
+        }
+    }
+
+    // Checks that the file was encrypted with this key, decrypting as little of it as possible
+    pub fn check(&self, path: &Path) -> Result<()> {
+        self.open(path).map(|_| ())
+    }
+
+    // Opens a file that was encrypted with this key, to be read a segment at a time
+    pub fn open(&self, path: &Path) -> Result<Decryptor<BufReader<File>>> {
+        self.decryptor(BufReader::new(File::open(path)?), &path.display().to_string())
+    }
+
+    // Starts decrypting the input, which is called `name` in errors
+    // The first segment is decrypted right away, so a wrong key is noticed before anything is read
+    fn decryptor<R: Read>(&self, mut input: R, name: &str) -> Result<Decryptor<R>> {
+        let mut start = Vec::new();
+        input.by_ref().take(CHUNKED_MAGIC.len() as u64).read_to_end(&mut start)?;
+        let mut decryptor = Decryptor {
+            input,
+            fernet: self.fernet(),
+            name: name.to_owned(),
+            size: 0,
+            segment: Zeroizing::new(Vec::new()),
+            position: 0,
+            index: 0,
+            written: 0,
+            last: false,
+        };
+        if start != CHUNKED_MAGIC {
+            // It's a single fernet token
+            let mut ciphertext = start;
+            decryptor.input.read_to_end(&mut ciphertext)?;
+            let ciphertext = String::from_utf8(ciphertext).map_err(|_| Error::WrongPassword)?;
+            decryptor.segment = Zeroizing::new(
+                decryptor.fernet.decrypt(ciphertext.trim()).map_err(|_| Error::WrongPassword)?
+            );
+            decryptor.size = decryptor.segment.len() as u64;
+            decryptor.last = true;
+            return Ok(decryptor);
+        }
+        let mut size = [0; 8];
+        decryptor.input.read_exact(&mut size).map_err(|_| decryptor.malformed("the file is cut off"))?;
+        decryptor.size = u64::from_be_bytes(size);
+        decryptor.next_segment()?;
+        Ok(decryptor)
+    }
+
+    // Decrypts a whole file into memory
+    pub fn decrypt_to_vec(&self, path: &Path) -> Result<Zeroizing<Vec<u8>>> {
+        // The ciphertext is always longer than the plaintext, so the buffer never has to grow
+        // (which would leave a copy of the plaintext behind)
+        let mut plaintext = Zeroizing::new(Vec::with_capacity(fs::metadata(path)?.len() as usize));
+        self.decrypt(path, &mut *plaintext, &Progress::default())?;
+        Ok(plaintext)
+    }
+}
+
+// The plaintext of an encrypted file, which is decrypted one segment at a time as it's read
+pub struct Decryptor<R> {
+    input: R,
+    fernet: fernet::Fernet,
+    name: String,
+    // The size of the plaintext, from the start of the file
+    size: u64,
+    // The segment that's being read (including its index and last segment flag), and how much of
+    // it has been read
+    segment: Zeroizing<Vec<u8>>,
+    position: usize,
+    // The index of the next segment
+    index: u64,
+    // How much plaintext has been decrypted
+    written: u64,
+    last: bool,
+}
+
+impl<R: Read> Decryptor<R> {
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn malformed(&self, details: &str) -> Error {
+        Error::MalformedData(format!("{}: {}", self.name, details))
+    }
+
+    // The part of the current segment that hasn't been read yet
+    fn unread(&self) -> &[u8] {
+        &self.segment[self.position..]
+    }
+
+    // Decrypts the next segment, returning false if the last one has already been decrypted
+    fn next_segment(&mut self) -> Result<bool> {
+        if self.last {
+            return Ok(false);
+        }
+        let mut length = [0; 4];
+        self.input.read_exact(&mut length).map_err(|_| self.malformed("the file is cut off"))?;
+        let length = u32::from_be_bytes(length) as usize;
+        // Fernet tokens are a bit over 4/3 as long as their plaintext
+        if length > 2 * (SEGMENT_HEADER_SIZE + SEGMENT_SIZE) {
+            return Err(self.malformed("a segment is too long"));
+        }
+        let mut token = vec![0; length];
+        self.input.read_exact(&mut token).map_err(|_| self.malformed("the file is cut off"))?;
+        let segment = std::str::from_utf8(&token).ok().and_then(|token| self.fernet.decrypt(token).ok());
+        self.segment = match segment {
+            Some(segment) => Zeroizing::new(segment),
+            None if self.index == 0 => return Err(Error::WrongPassword),
+            None => return Err(self.malformed("a segment couldn't be decrypted")),
+        };
+        if self.segment.len() < SEGMENT_HEADER_SIZE || self.segment[..8] != self.index.to_be_bytes() {
+            return Err(self.malformed("the segments are out of order"));
+        }
+        self.position = SEGMENT_HEADER_SIZE;
+        self.index += 1;
+        self.written += (self.segment.len() - SEGMENT_HEADER_SIZE) as u64;
+        self.last = self.segment[8] == 1;
+        if (self.last && self.written != self.size) || self.written > self.size {
+            return Err(self.malformed("the file is cut off"));
+        }
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for Decryptor<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        while self.unread().is_empty() {
+            if !self.next_segment().map_err(io::Error::other)? {
+                return Ok(0);
+            }
+        }
+        let length = self.unread().len().min(buffer.len());
+        buffer[..length].copy_from_slice(&self.unread()[..length]);
+        self.position += length;
+        Ok(length)
+    }
+}
+
+// Errors from reading a Decryptor are the cheesecake errors that caused them
+fn read_error(e: io::Error) -> Error {
+    if e.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+        *e.into_inner().unwrap().downcast::<Error>().unwrap()
+    } else {
+        Error::Io(e)
+    }
+}
+
+// Derives the key of an encrypted backup from its password
+// The password isn't checked until something is decrypted with the key
+pub fn backup_key(refrigerator: &Path, backup_path: &str, password: &str) -> Result<Key> {
+    let info = read_info(refrigerator, backup_path)?;
+    let salt = info["salt"].as_str().ok_or(Error::NotEncrypted)?;
+    let salt = base64::decode_config(salt, base64::URL_SAFE).map_err(|_| Error::InvalidInfo("salt"))?;
+    Key::derive(password, &salt, &Kdf::from_info(&info)?)
+}
+
+// Replaces the database with the plaintext encrypted with a new password, and stores the salt and
+// KDF in info.json
+// If the backup was already encrypted with `old_key`, its encrypted assets are encrypted again too
+pub fn write_encrypted(refrigerator: &Path, backup_path: &str, plaintext: &mut dyn Read, size: u64, password: &str, kdf: &Kdf, old_key: Option<&Key>) -> Result<()> {
+    let mut info = read_info(refrigerator, backup_path)?;
+    let mut salt = [0; 16];
+    openssl::rand::rand_bytes(&mut salt).map_err(io::Error::other)?;
+    let key = Key::derive(password, &salt, kdf)?;
+    info["salt"] = base64::encode_config(salt, base64::URL_SAFE).into();
+    kdf.write_info(&mut info)?;
+    // Every file is written out before any of them is replaced, so that they can only get out of
+    // sync if cheesecake is interrupted while renaming them. info.json is replaced last, so that it
+    // never has the new salt and KDF before the database is encrypted with them
+    let mut files = vec![
+        (database_path(refrigerator, backup_path), encrypted_temp_file(&key, &database_path(refrigerator, backup_path), plaintext, size)?),
+    ];
+    if let Some(old_key) = old_key {
+        for path in asset_files(refrigerator, backup_path)? {
+            if !is_encrypted_asset(&info, &path)? {
+                continue;
+            }
+            let mut asset = old_key.open(&path)?;
+            let size = asset.size();
+            let file = encrypted_temp_file(&key, &path, &mut asset, size)?;
+            files.push((path, file));
+        }
+    }
+    files.push((info_path(refrigerator, backup_path), temp_file(&info_path(refrigerator, backup_path), serde_json::to_string(&info)?.as_bytes())?));
+    for (path, file) in files {
+        file.persist(path).map_err(|e| e.error)?;
+    }
+    Ok(())
+}
+
+// Checks the password that the key was derived from
+pub fn check_key(refrigerator: &Path, backup_path: &str, key: &Key) -> Result<()> {
+    key.check(&database_path(refrigerator, backup_path))
+}
+
+pub fn is_encrypted(refrigerator: &Path, backup_path: &str) -> Result<bool> {
+    Ok(read_info(refrigerator, backup_path)?["salt"].is_string())
+}
+
+// Encrypts a plaintext backup
+pub fn encrypt(refrigerator: &Path, backup_path: &str, password: &str, kdf: &Kdf) -> Result<()> {
+    if is_encrypted(refrigerator, backup_path)? {
+        return Err(Error::AlreadyEncrypted);
+    }
+    let mut database = File::open(database_path(refrigerator, backup_path))?;
+    let size = database.metadata()?.len();
+    write_encrypted(refrigerator, backup_path, &mut BufReader::new(&mut database), size, password, kdf, None)
+}
+
+// Writes a decrypted copy of the backup's database to `output`, which must not exist yet
+pub fn decrypt_to_file(refrigerator: &Path, backup_path: &str, key: &Key, output: &Path) -> Result<()> {
+    let file = OpenOptions::new().write(true).create_new(true).open(output)?;
+    let mut file = BufWriter::new(file);
+    key.decrypt(&database_path(refrigerator, backup_path), &mut file, &Progress::default())?;
+    file.flush()?;
+    Ok(())
+}
+
+// Encrypted assets are stored next to where the plain asset would be, with `.enc` appended to
+// the file name
+pub fn encrypted_asset_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_owned();
+    file_name.push(".enc");
+    path.with_file_name(file_name)
+}
+
+// Whether the file is an asset that encrypt_assets encrypted, which only backups with encrypted
+// assets have. Those are always in the segmented format, so a file that came in the backup with a
+// `.enc` name but doesn't start with CHUNKED_MAGIC is a plain asset, and is left as it is
+fn is_encrypted_asset(info: &serde_json::Value, path: &Path) -> Result<bool> {
+    if !has_encrypted_assets(info) || path.extension() != Some(OsStr::new("enc")) {
+        return Ok(false);
+    }
+    let mut start = Vec::new();
+    File::open(path)?.take(CHUNKED_MAGIC.len() as u64).read_to_end(&mut start)?;
+    Ok(start == CHUNKED_MAGIC)
+}
+
+// Whether info.json says the assets of the backup are encrypted
+pub fn has_encrypted_assets(info: &serde_json::Value) -> bool {
+    info["encrypted_assets"].as_bool().unwrap_or(false)
+}
+
+// Lists the files in the asset directories of the backup
+fn asset_files(refrigerator: &Path, backup_path: &str) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for asset_type in [Avatar, Attachment, Emoji] {
+        let pattern = refrigerator.join(backup_path).join(asset_type.dir()).join("**/*");
+        for path in glob(&pattern.to_string_lossy()).into_iter().flatten() {
+            let path = path.map_err(|e| e.into_error())?;
+            if path.is_file() {
+                files.push(path);
+            }
+        }
+    }
+    Ok(files)
+}
+
+// Encrypts the assets of an encrypted backup, replacing each file with an encrypted copy
+// Returns the number of assets that were encrypted
+pub fn encrypt_assets(refrigerator: &Path, backup_path: &str, key: &Key) -> Result<usize> {
+    // The server serves plain assets until they have been replaced, so the flag can be set first
+    let mut info = read_info(refrigerator, backup_path)?;
+    if !has_encrypted_assets(&info) {
+        info["encrypted_assets"] = true.into();
+        temp_file(&info_path(refrigerator, backup_path), serde_json::to_string(&info)?.as_bytes())?
+            .persist(info_path(refrigerator, backup_path))
+            .map_err(|e| e.error)?;
+    }
+    let mut count = 0;
+    for path in asset_files(refrigerator, backup_path)? {
+        if is_encrypted_asset(&info, &path)? {
+            continue;
+        }
+        let mut asset = File::open(&path)?;
+        let size = asset.metadata()?.len();
+        encrypted_temp_file(key, &path, &mut BufReader::new(&mut asset), size)?
+            .persist(encrypted_asset_path(&path))
+            .map_err(|e| e.error)?;
+        fs::remove_file(&path)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+// Writes the contents to a temporary file next to `path`, which can then replace it in one step
+fn temp_file(path: &Path, contents: &[u8]) -> Result<NamedTempFile> {
+    let mut file = NamedTempFile::new_in(path.parent().unwrap_or_else(|| Path::new(".")))?;
+    file.write_all(contents)?;
+    file.as_file().sync_all()?;
+    Ok(file)
+}
+
+// Like temp_file, but encrypts `size` bytes read from `plaintext` into it
+fn encrypted_temp_file(key: &Key, path: &Path, plaintext: &mut dyn Read, size: u64) -> Result<NamedTempFile> {
+    let mut file = NamedTempFile::new_in(path.parent().unwrap_or_else(|| Path::new(".")))?;
+    let mut writer = BufWriter::new(&mut file);
+    key.encrypt(plaintext, size, &mut writer)?;
+    writer.flush()?;
+    drop(writer);
+    file.as_file().sync_all()?;
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key() -> Key {
+        Key(Zeroizing::new(fernet::Fernet::generate_key()))
+    }
+
+    fn encrypted(key: &Key, plaintext: &[u8]) -> Vec<u8> {
+        let mut output = Vec::new();
+        key.encrypt(&mut &plaintext[..], plaintext.len() as u64, &mut output).unwrap();
+        output
+    }
+
+    fn decrypted(key: &Key, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        let mut plaintext = Vec::new();
+        key.decryptor(ciphertext, "test")?.read_to_end(&mut plaintext).map_err(read_error)?;
+        Ok(plaintext)
+    }
+
+    // Splits an encrypted file into its header and the segments (with their lengths)
+    fn segments(ciphertext: &[u8]) -> (Vec<u8>, Vec<Vec<u8>>) {
+        let header_size = CHUNKED_MAGIC.len() + 8;
+        let mut segments = Vec::new();
+        let mut rest = &ciphertext[header_size..];
+        while !rest.is_empty() {
+            let length = 4 + u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            segments.push(rest[..length].to_vec());
+            rest = &rest[length..];
+        }
+        (ciphertext[..header_size].to_vec(), segments)
+    }
+
+    // A plaintext that takes three segments
+    fn plaintext() -> Vec<u8> {
+        (0..2 * SEGMENT_SIZE + 123).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let key = key();
+        let plaintext = plaintext();
+        let ciphertext = encrypted(&key, &plaintext);
+        assert_eq!(segments(&ciphertext).1.len(), 3);
+        assert_eq!(decrypted(&key, &ciphertext).unwrap(), plaintext);
+        assert_eq!(decrypted(&key, &encrypted(&key, b"")).unwrap(), b"");
+    }
+
+    #[test]
+    fn reordered_segments() {
+        let key = key();
+        let (header, mut segments) = segments(&encrypted(&key, &plaintext()));
+        segments.swap(1, 2);
+        let ciphertext = [header, segments.concat()].concat();
+        assert!(matches!(decrypted(&key, &ciphertext), Err(Error::MalformedData(_))));
+    }
+
+    #[test]
+    fn truncated_file() {
+        let key = key();
+        let ciphertext = encrypted(&key, &plaintext());
+        // Without the last segment
+        let (header, segments) = segments(&ciphertext);
+        let without_last = [header, segments[..2].concat()].concat();
+        assert!(matches!(decrypted(&key, &without_last), Err(Error::MalformedData(_))));
+        // Cut off in the middle of a segment
+        let cut_off = &ciphertext[..ciphertext.len() - 10];
+        assert!(matches!(decrypted(&key, cut_off), Err(Error::MalformedData(_))));
+        // Cut off in the header
+        assert!(matches!(decrypted(&key, &ciphertext[..CHUNKED_MAGIC.len() + 3]), Err(Error::MalformedData(_))));
+    }
+
+    #[test]
+    fn wrong_key() {
+        let ciphertext = encrypted(&key(), &plaintext());
+        assert!(matches!(key().decryptor(&ciphertext[..], "test"), Err(Error::WrongPassword)));
+    }
+
+    #[test]
+    fn single_token() {
+        let key = key();
+        let ciphertext = format!("{}\n", key.fernet().encrypt(b"a legacy database"));
+        let mut decryptor = key.decryptor(ciphertext.as_bytes(), "test").unwrap();
+        assert_eq!(decryptor.size(), 17);
+        let mut plaintext = Vec::new();
+        decryptor.read_to_end(&mut plaintext).unwrap();
+        assert_eq!(plaintext, b"a legacy database");
+        assert!(matches!(self::key().decryptor(ciphertext.as_bytes(), "test"), Err(Error::WrongPassword)));
+    }
+
+    #[test]
+    fn plain_assets_named_enc() {
+        let refrigerator = tempfile::tempdir().unwrap();
+        let refrigerator = refrigerator.path();
+        let attachments = refrigerator.join("backup").join(Attachment.dir());
+        fs::create_dir_all(&attachments).unwrap();
+        fs::write(info_path(refrigerator, "backup"), r#"{"version": "0.1.0", "type": "matrix", "encrypted_assets": true}"#).unwrap();
+        let old_key = key();
+        fs::write(attachments.join("cake.png.enc"), encrypted(&old_key, b"an encrypted attachment")).unwrap();
+        fs::write(attachments.join("notes.enc"), b"a plain attachment").unwrap();
+        let info = read_info(refrigerator, "backup").unwrap();
+        assert!(is_encrypted_asset(&info, &attachments.join("cake.png.enc")).unwrap());
+        assert!(!is_encrypted_asset(&info, &attachments.join("notes.enc")).unwrap());
+        assert!(!is_encrypted_asset(&serde_json::json!({}), &attachments.join("cake.png.enc")).unwrap());
+
+        let kdf = Kdf::Pbkdf2 { iterations: 1_000 };
+        write_encrypted(refrigerator, "backup", &mut &b"database"[..], 8, "password", &kdf, Some(&old_key)).unwrap();
+        let new_key = backup_key(refrigerator, "backup", "password").unwrap();
+        assert_eq!(decrypted(&new_key, &fs::read(attachments.join("cake.png.enc")).unwrap()).unwrap(), b"an encrypted attachment");
+        assert_eq!(fs::read(attachments.join("notes.enc")).unwrap(), b"a plain attachment");
+    }
+
+    #[test]
+    fn kdf_limits() {
+        for iterations in [0, 999, 10_000_001, u64::from(u32::MAX) + 1] {
+            let info = serde_json::json!({ "iterations": iterations });
+            assert!(matches!(Kdf::from_info(&info), Err(Error::InvalidInfo("iterations"))));
+        }
+        assert!(Kdf::from_info(&serde_json::json!({ "iterations": 100_000 })).is_ok());
+        let kdf = Kdf::Argon2id { memory: u32::MAX, iterations: 1, parallelism: 1 };
+        assert!(matches!(kdf.derive("password", b"saltsaltsaltsalt", &mut [0; 32]), Err(Error::InvalidInfo("kdf"))));
+        for kdf in [
+            serde_json::json!({ "name": "argon2id", "memory": 65536, "iterations": 0, "parallelism": 4 }),
+            serde_json::json!({ "name": "argon2id", "memory": 65536, "iterations": 1000, "parallelism": 4 }),
+            serde_json::json!({ "name": "argon2id", "memory": 65536, "iterations": 3, "parallelism": 0 }),
+            serde_json::json!({ "name": "argon2id", "memory": 65536, "iterations": 3, "parallelism": 1000 }),
+            serde_json::json!({ "name": "scrypt", "log_n": 0, "r": 8, "p": 1 }),
+            serde_json::json!({ "name": "scrypt", "log_n": 17, "r": 0, "p": 1 }),
+            serde_json::json!({ "name": "scrypt", "log_n": 17, "r": 8, "p": 0 }),
+            serde_json::json!({ "name": "scrypt", "log_n": 17, "r": 8, "p": 1000 }),
+        ] {
+            assert!(matches!(Kdf::from_info(&serde_json::json!({ "kdf": kdf })), Err(Error::InvalidInfo("kdf"))));
+        }
+        // The parameters cheesecake uses are within the limits
+        for name in ["pbkdf2", "argon2id", "scrypt"] {
+            let mut info = serde_json::json!({});
+            Kdf::from_name(name).unwrap().write_info(&mut info).unwrap();
+            assert!(Kdf::from_info(&info).is_ok());
+        }
+    }
+}
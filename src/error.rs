@@ -0,0 +1,86 @@
+use std::{fmt, io};
+
+// Everything that can go wrong while reading a backup
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Json(serde_json::Error),
+    Database(rusqlite::Error),
+    // A key in info.json is missing or has the wrong type
+    InvalidInfo(&'static str),
+    UnsupportedVersion(String),
+    UnknownFormat(String),
+    // A backup path that isn't the name of a directory in the refrigerator, like `../secrets`
+    InvalidBackupPath(String),
+    // A value in the database couldn't be understood
+    MalformedData(String),
+    // A cookie or form field the request needs wasn't sent
+    MissingParameter(&'static str),
+    ChatNotFound(String),
+    MessageNotFound(String),
+    // The backup is encrypted and hasn't been decrypted yet
+    Locked,
+    WrongPassword,
+    // Encrypting a backup that already is
+    AlreadyEncrypted,
+    // Decrypting a backup that isn't encrypted
+    NotEncrypted,
+    UnknownKdf(String),
+    // A search filter couldn't be parsed
+    InvalidFilter(String),
+    // A raw SQL search filter was used without being allowed in the settings
+    SqlFiltersDisabled,
+    // A date to jump to couldn't be parsed
+    InvalidDate(String),
+    // The query parameters of an API request couldn't be parsed
+    InvalidParameters(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{}", e),
+            Self::Json(e) => write!(f, "invalid JSON: {}", e),
+            Self::Database(e) => write!(f, "database error: {}", e),
+            Self::InvalidInfo(key) => write!(f, "`{}` is missing or invalid in info.json", key),
+            Self::UnsupportedVersion(version) => write!(f, "unsupported backup version `{}`", version),
+            Self::UnknownFormat(format) => write!(f, "unknown backup type `{}`", format),
+            Self::InvalidBackupPath(path) => write!(f, "`{}` is not a backup in the refrigerator", path),
+            Self::MalformedData(details) => write!(f, "malformed backup: {}", details),
+            Self::MissingParameter(name) => write!(f, "`{}` was not specified", name),
+            Self::ChatNotFound(id) => write!(f, "chat `{}` was not found", id),
+            Self::MessageNotFound(id) => write!(f, "message `{}` was not found", id),
+            Self::Locked => write!(f, "the backup is encrypted and has not been decrypted"),
+            Self::WrongPassword => write!(f, "incorrect password"),
+            Self::AlreadyEncrypted => write!(f, "the backup is already encrypted"),
+            Self::NotEncrypted => write!(f, "the backup is not encrypted"),
+            Self::UnknownKdf(name) => write!(f, "unknown key derivation function `{}`", name),
+            Self::InvalidFilter(term) => write!(f, "`{}` is not a valid filter", term),
+            Self::SqlFiltersDisabled => write!(f, "SQL filters are not allowed"),
+            Self::InvalidDate(date) => write!(f, "`{}` is not a valid date", date),
+            Self::InvalidParameters(errors) => write!(f, "invalid parameters: {}", errors),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Json(e)
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        Self::Database(e)
+    }
+}
@@ -0,0 +1,972 @@
+// Exports chats to files that can be read without cheesecake
+use chrono::NaiveDateTime;
+use rocket_dyn_templates::tera::{Context, Tera};
+use std::{collections::HashSet, fs::{self, File}, io::{self, BufWriter, Write}, path::{Component, Path, PathBuf}};
+use cheesecake::{
+    actions::{self, Chat, Message, Refrigerator},
+    decrypted::DecryptedBackup,
+    error::Result,
+    format::{AssetType, Page, Search, SearchOrder},
+    index,
+    session::Session,
+};
+use super::static_include::Assets;
+
+// How many messages each page of a chat exported to HTML has
+const PAGE_SIZE: u64 = 500;
+// How many search results are fetched at a time
+const SEARCH_PAGE_SIZE: usize = 500;
+
+// The assets of a backup that an export uses, as their types and paths in the backup
+type LocalAssets = HashSet<(AssetType, PathBuf)>;
+
+// The files in `static` that exported pages use
+const STATIC_FILES: [&str; 5] = [
+    "styles/reader.css",
+    "scripts/jquery-3.6.0.min.js",
+    "scripts/export.js",
+    "fonts/SourceSansPro-Regular.ttf",
+    "images/default.svg",
+];
+
+// A chat in the chat switcher of exported pages
+#[derive(Serialize)]
+struct ExportedChat {
+    name: String,
+    // The newest page of the chat, which is where it opens
+    href: String,
+}
+
+// What the reader template needs to know about the exported page it's rendering
+#[derive(Serialize)]
+struct ExportedPage<'a> {
+    chats: &'a [ExportedChat],
+    // The index of the chat in `chats`
+    chat: usize,
+    page: String,
+    number: u64,
+    pages: u64,
+    previous: Option<String>,
+    next: Option<String>,
+}
+
+#[derive(Serialize)]
+struct PageContext<'a> {
+    export: ExportedPage<'a>,
+    name: &'a str,
+    topic: &'a str,
+    messages: &'a [Message],
+}
+
+// The formats messages can be exported to other than HTML, which exports whole chats
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    Csv,
+    // Lines like `[2021-03-14 18:30] Jane Doe: hi`
+    Transcript,
+    // CommonMark, with the attachments as links relative to the document
+    Markdown,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "json" => Some(Self::Json),
+            "csv" => Some(Self::Csv),
+            "transcript" => Some(Self::Transcript),
+            "markdown" => Some(Self::Markdown),
+            _ => None,
+        }
+    }
+}
+
+// The messages sent in a span of time, which is the whole chat unless it's limited
+// Times are local times, compared with the timestamps of messages, which are too
+#[derive(Default)]
+pub struct TimeRange {
+    // Including messages sent at the time
+    pub since: Option<NaiveDateTime>,
+    pub until: Option<NaiveDateTime>,
+}
+
+impl TimeRange {
+    pub fn is_whole(&self) -> bool {
+        self.since.is_none() && self.until.is_none()
+    }
+
+    fn timestamp(time: &Option<NaiveDateTime>) -> Option<String> {
+        time.map(|time| time.format("%Y-%m-%d %H:%M").to_string())
+    }
+}
+
+// A message the way it's exported to JSON, which is the same for every format of backup
+#[derive(Serialize)]
+struct ExportedMessage<'a> {
+    sequential_id: &'a str,
+    id: &'a str,
+    chat_id: &'a str,
+    chat: &'a str,
+    #[serde(rename = "type")]
+    message_type: &'a str,
+    author: &'a str,
+    timestamp: &'a str,
+    edited_timestamp: Option<&'a str>,
+    // The sequential ID of the message this one replies to
+    reply_to: Option<&'a str>,
+    text: String,
+    // The content the way the reader shows it
+    html: &'a str,
+    // Paths relative to the refrigerator, or URLs for assets that aren't saved in the backup
+    attachments: Vec<&'a str>,
+    reactions: Vec<ExportedReaction<'a>>,
+}
+
+#[derive(Serialize)]
+struct ExportedReaction<'a> {
+    name: &'a str,
+    count: usize,
+}
+
+// Writes the messages that were sent in the time range to the output in one of the formats, one at
+// a time
+pub struct Writer<'a> {
+    format: Format,
+    // The timestamps the range starts and ends at
+    since: Option<String>,
+    until: Option<String>,
+    output: &'a mut dyn Write,
+    // Transcripts of more than one chat say which chat each message is from
+    many_chats: bool,
+    // The URLs of the attachments Markdown links to, so that they can be copied next to it
+    attachments: Option<&'a mut HashSet<String>>,
+    count: u64,
+}
+
+impl<'a> Writer<'a> {
+    pub fn new(format: Format, range: &TimeRange, attachments: Option<&'a mut HashSet<String>>, output: &'a mut dyn Write) -> Result<Self> {
+        match format {
+            Format::Json => write!(output, "[")?,
+            Format::Csv => writeln!(output, "id,chat,timestamp,author,content,attachments")?,
+            Format::Transcript | Format::Markdown => {}
+        }
+        Ok(Writer {
+            format,
+            since: TimeRange::timestamp(&range.since),
+            until: TimeRange::timestamp(&range.until),
+            output,
+            many_chats: false,
+            attachments,
+            count: 0,
+        })
+    }
+
+    // Whether the message was sent after the time range, and so are the ones after it in the chat
+    fn is_past(&self, message: &Message) -> bool {
+        self.until.as_ref().is_some_and(|until| &message.created_timestamp >= until)
+    }
+
+    fn write(&mut self, chat: &Chat, message: &Message) -> Result<()> {
+        if self.since.as_ref().is_some_and(|since| &message.created_timestamp < since) || self.is_past(message) {
+            return Ok(());
+        }
+        let text = plain_text(&message.content);
+        let attachments: Vec<&str> = message.attachments.iter().map(|attachment| attachment.0.as_str()).collect();
+        match self.format {
+            Format::Json => {
+                writeln!(self.output, "{}", if self.count == 0 {""} else {","})?;
+                serde_json::to_writer(&mut *self.output, &ExportedMessage {
+                    sequential_id: &message.sequential_id,
+                    id: &message.message_id,
+                    chat_id: &chat.id,
+                    chat: &chat.name,
+                    message_type: &message.message_type,
+                    author: &message.name,
+                    timestamp: &message.created_timestamp,
+                    edited_timestamp: message.edited_timestamp.as_deref(),
+                    reply_to: message.reference.as_ref().map(|reference| reference.0.as_str()),
+                    text,
+                    html: &message.content,
+                    attachments,
+                    reactions: message.reactions
+                        .iter()
+                        .map(|reaction| ExportedReaction {name: &reaction.0, count: reaction.2})
+                        .collect(),
+                })?;
+            }
+            Format::Csv => writeln!(
+                self.output,
+                "{},{},{},{},{},{}",
+                csv_field(&message.message_id),
+                csv_field(&chat.name),
+                csv_field(&message.created_timestamp),
+                csv_field(&message.name),
+                csv_field(&text),
+                // Attachment names can have commas and spaces, but not line breaks
+                csv_field(&attachments.join("\n")),
+            )?,
+            Format::Transcript => {
+                write!(self.output, "[{}] ", message.created_timestamp)?;
+                if self.many_chats {
+                    write!(self.output, "({}) ", chat.name)?;
+                }
+                // Lines after the first are indented so that they can be told apart from messages
+                write!(self.output, "{}: {}", message.name, text.replace('\n', "\n    "))?;
+                for attachment in attachments {
+                    write!(self.output, " [attachment: {}]", attachment)?;
+                }
+                writeln!(self.output)?;
+            }
+            Format::Markdown => {
+                if self.count > 0 {
+                    writeln!(self.output)?;
+                }
+                write!(self.output, "**{}** {}", escape_markdown(&message.name, false), message.created_timestamp)?;
+                if self.many_chats {
+                    write!(self.output, " in {}", escape_markdown(&chat.name, false))?;
+                }
+                if message.edited_timestamp.is_some() {
+                    write!(self.output, " (edited)")?;
+                }
+                writeln!(self.output)?;
+                if let Some(reference) = &message.reference {
+                    // The reader shows the replied message as text, the way it's saved
+                    let mut snippet = reference.4.replace('\n', " ");
+                    if snippet.is_empty() && reference.5 {
+                        snippet = String::from("(attachment)");
+                    }
+                    writeln!(self.output, "\n> **{}**: {}", escape_markdown(&reference.1, false), escape_markdown(&snippet, false))?;
+                }
+                let content = markdown(&message.content);
+                if !content.is_empty() {
+                    writeln!(self.output, "\n{}", content)?;
+                }
+                for (url, attachment_type, _) in &message.attachments {
+                    // Attachments saved in the backup are copied to the same path relative to the
+                    // document, so their URLs work as relative links
+                    let name = url.rsplit('/').next().unwrap_or_default();
+                    let image = if attachment_type == "image" {"!"} else {""};
+                    writeln!(self.output, "\n{}[{}]({})", image, escape_markdown(name, false), link_destination(url))?;
+                    if let Some(attachments) = self.attachments.as_mut() {
+                        attachments.insert(url.clone());
+                    }
+                }
+                if !message.reactions.is_empty() {
+                    let reactions: Vec<_> = message.reactions
+                        .iter()
+                        .map(|(name, _, count)| format!("{} {}", escape_markdown(name, false), count))
+                        .collect();
+                    writeln!(self.output, "\n{}", reactions.join(" · "))?;
+                }
+            }
+        }
+        self.count += 1;
+        Ok(())
+    }
+
+    // Returns the number of messages that were written
+    pub fn finish(self) -> Result<u64> {
+        if self.format == Format::Json {
+            writeln!(self.output, "{}]", if self.count == 0 {""} else {"\n"})?;
+        }
+        self.output.flush()?;
+        Ok(self.count)
+    }
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+// Exports the messages of the chat with the writer
+// Returns the number of messages that were written
+pub fn chat(refrigerator: &Refrigerator, decrypted: Option<&DecryptedBackup>, backup_path: &str, chat: &Chat, mut writer: Writer) -> Result<u64> {
+    for message in actions::chat_messages(refrigerator, decrypted, backup_path, &chat.id) {
+        let message = message?;
+        if writer.is_past(&message) {
+            break;
+        }
+        writer.write(chat, &message)?;
+    }
+    writer.finish()
+}
+
+// Exports the results of searching the backup, or only the chat if one is given, with the writer,
+// oldest first
+// The search's page is ignored, and every result is exported
+pub fn search(refrigerator: &Refrigerator, decrypted: Option<&DecryptedBackup>, backup_path: &str, chat_id: Option<&str>, search: &Search, index_options: index::Options, mut writer: Writer) -> Result<u64> {
+    writer.many_chats = chat_id.is_none();
+    let page = Page {order: SearchOrder::Oldest, limit: SEARCH_PAGE_SIZE, offset: 0};
+    actions::search_pages(refrigerator, decrypted, backup_path, chat_id, &Search {page, ..*search}, index_options, |hits| {
+        for hit in &hits {
+            let chat = Chat {
+                id: hit.chat_id.clone(),
+                name: hit.chat_name.clone(),
+                topic: String::new(),
+            };
+            writer.write(&chat, &hit.message)?;
+        }
+        Ok(())
+    })?;
+    writer.finish()
+}
+
+// Renders the chats into pages that look like the reader into the output directory, along with the
+// styles, scripts and assets they use, and a search index for searching them in the browser
+// The backup has to be decrypted in the session if it's encrypted, and its assets are decrypted too
+// Returns the number of messages
+pub fn html(refrigerator: &Refrigerator, session: &Session, backup_path: &str, chats: &[Chat], output: &Path) -> Result<u64> {
+    let decrypted = session.decrypted(backup_path);
+    let mut tera = Tera::default();
+    super::customize(&mut tera);
+
+    let mut exported = Vec::new();
+    let mut page_counts = Vec::new();
+    for (i, chat) in chats.iter().enumerate() {
+        let count = actions::message_count(refrigerator, decrypted, backup_path, &chat.id)?;
+        // Chats without messages still get an empty page
+        let pages = count.div_ceil(PAGE_SIZE).max(1);
+        exported.push(ExportedChat {
+            name: chat.name.clone(),
+            href: format!("{}#end", page_path(i, pages)),
+        });
+        page_counts.push(pages);
+    }
+
+    fs::create_dir_all(output)?;
+    for file in STATIC_FILES {
+        let path = output.join(file);
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(path, Assets::get(file).unwrap().data)?;
+    }
+    fs::write(output.join("index.html"), format!(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n    <meta charset=\"UTF-8\">\n    <meta http-equiv=\"refresh\" content=\"0; url={0}\">\n    <title>cheesecake</title>\n</head>\n<body>\n    <a href=\"{0}\">{1}</a>\n</body>\n</html>\n",
+        exported[0].href,
+        html_escape::encode_text(&exported[0].name),
+    ))?;
+
+    // The search index is a script rather than JSON, because browsers don't let pages opened from
+    // files fetch other files
+    let mut index = BufWriter::new(File::create(output.join("search.js"))?);
+    let names: Vec<_> = chats.iter().map(|chat| &chat.name).collect();
+    write!(index, "var search_index = {{\"chats\": {}, \"messages\": [", serde_json::to_string(&names)?)?;
+    let mut assets = LocalAssets::new();
+    let mut total = 0;
+    for (i, chat) in chats.iter().enumerate() {
+        fs::create_dir_all(output.join("chats").join((i + 1).to_string()))?;
+        let mut messages = actions::chat_messages(refrigerator, decrypted, backup_path, &chat.id);
+        let mut previous_date = None;
+        for number in 1..=page_counts[i] {
+            let page = page_path(i, number);
+            let mut page_messages = Vec::new();
+            for message in messages.by_ref().take(PAGE_SIZE as usize) {
+                let mut message = message?;
+                // The timestamp starts with the date
+                let date = message.created_timestamp.get(..10).unwrap_or_default().to_owned();
+                if previous_date.as_ref().is_some_and(|previous| previous != &date) {
+                    // Like the ones populate_messages adds, which are left out of chat_messages
+                    page_messages.push(Message {
+                        message_type: String::from("day_separator"),
+                        content: date.clone(),
+                        ..Default::default()
+                    });
+                }
+                previous_date = Some(date);
+                local_assets(&mut message, backup_path, &mut assets);
+                if total > 0 {
+                    write!(index, ",")?;
+                }
+                serde_json::to_writer(&mut index, &(
+                    i,
+                    &page,
+                    &message.sequential_id,
+                    &message.name,
+                    &message.created_timestamp,
+                    plain_text(&message.content),
+                ))?;
+                total += 1;
+                page_messages.push(message);
+            }
+            let context = PageContext {
+                export: ExportedPage {
+                    chats: &exported,
+                    chat: i,
+                    page: page.clone(),
+                    number,
+                    pages: page_counts[i],
+                    previous: (number > 1).then(|| page_path(i, number - 1)),
+                    next: (number < page_counts[i]).then(|| page_path(i, number + 1)),
+                },
+                name: &chat.name,
+                topic: &chat.topic,
+                messages: &page_messages,
+            };
+            let html = Context::from_serialize(&context)
+                .and_then(|context| tera.render("reader.html", &context))
+                .map_err(io::Error::other)?;
+            fs::write(output.join(&page), html)?;
+        }
+    }
+    writeln!(index, "]}};")?;
+    index.flush()?;
+
+    copy_assets(refrigerator, session, backup_path, &assets, output)?;
+    Ok(total)
+}
+
+// Copies the assets into the output directory, at the same paths relative to it as the URLs
+// `generic::url` gives them are relative to the refrigerator, decrypting them if they're encrypted
+fn copy_assets(refrigerator: &Refrigerator, session: &Session, backup_path: &str, assets: &LocalAssets, output: &Path) -> Result<()> {
+    for (asset_type, path) in assets {
+        let source = refrigerator.path.join(backup_path).join(asset_type.dir()).join(path);
+        let destination = output.join(backup_path).join(asset_type.dir()).join(path);
+        fs::create_dir_all(destination.parent().unwrap())?;
+        match actions::decrypted_asset(refrigerator, session, backup_path, *asset_type, path)? {
+            Some(asset) => fs::write(destination, asset.as_slice())?,
+            // Assets that aren't saved in the backup are left out, like they're missing in the reader
+            None if source.is_file() => {
+                fs::copy(source, destination)?;
+            }
+            None => {}
+        }
+    }
+    Ok(())
+}
+
+// Copies the attachments that a Markdown export links to into the directory the document is in
+// Attachments that are links to other sites are left alone
+pub fn copy_attachments(refrigerator: &Refrigerator, session: &Session, backup_path: &str, urls: &HashSet<String>, output: &Path) -> Result<()> {
+    let assets = urls.iter().filter_map(|url| local_asset(url, backup_path)).collect();
+    copy_assets(refrigerator, session, backup_path, &assets, output)
+}
+
+fn page_path(chat: usize, number: u64) -> String {
+    format!("chats/{}/{}.html", chat + 1, number)
+}
+
+// Collects the assets of the backup that the message uses, and makes the paths of cheesecake's own
+// images relative, so that they're found in the export
+fn local_assets(message: &mut Message, backup_path: &str, assets: &mut LocalAssets) {
+    let mut add = |url: &str| assets.extend(local_asset(url, backup_path));
+    add(&message.avatar);
+    if let Some(reference) = &message.reference {
+        add(&reference.2);
+    }
+    for attachment in &message.attachments {
+        add(&attachment.0);
+    }
+    for reaction in &message.reactions {
+        if let Some(source) = &reaction.1 {
+            add(source);
+        }
+    }
+    // Custom emoji in the content
+    let prefix = format!("\"{}/", backup_path);
+    for (start, _) in message.content.match_indices(&prefix) {
+        let url = &message.content[start + 1..];
+        add(&html_escape::decode_html_entities(&url[..url.find('"').unwrap_or(url.len())]));
+    }
+    for url in [Some(&mut message.avatar), message.reference.as_mut().map(|reference| &mut reference.2)].into_iter().flatten() {
+        if let Some(relative) = url.strip_prefix('/') {
+            *url = relative.to_owned();
+        }
+    }
+}
+
+// The asset of the backup at the URL, if it's one of its assets
+fn local_asset(url: &str, backup_path: &str) -> Option<(AssetType, PathBuf)> {
+    let (dir, path) = url
+        .strip_prefix(backup_path)
+        .and_then(|url| url.strip_prefix('/'))
+        .and_then(|url| url.split_once('/'))?;
+    let path = PathBuf::from(path);
+    // Don't let the backup write outside of the export
+    if !path.components().all(|c| matches!(c, Component::Normal(_))) {
+        return None;
+    }
+    Some((AssetType::from_dir(dir)?, path))
+}
+
+// Turns the HTML of a message or snippet into the text it shows, keeping its line breaks, and with
+// custom emoji as their names
+pub fn plain_text(html: &str) -> String {
+    let mut text = String::new();
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        text.push_str(&rest[..start]);
+        let end = match rest[start..].find('>') {
+            Some(end) => start + end,
+            None => break,
+        };
+        let tag = &rest[start + 1..end];
+        let name = tag_name(tag);
+        if name == "br" || (tag.starts_with('/') && ["p", "div", "li", "blockquote", "pre", "h1", "h2", "h3", "h4", "h5", "h6"].contains(&name.as_str())) {
+            text.push('\n');
+        } else if name == "img" {
+            text.push_str(&attribute(tag, "alt").unwrap_or_default());
+        }
+        rest = &rest[end + 1..];
+    }
+    text.push_str(rest);
+    html_escape::decode_html_entities(text.trim_end()).into_owned()
+}
+
+// What an element of a message turns into in Markdown, which is decided when it's closed because
+// some of them depend on what's in them
+enum Markup {
+    // Text between a pair of markers, like `**` for bold text
+    Wrap(&'static str),
+    Code,
+    CodeBlock {
+        // From the class of the `code` element in it
+        language: Option<String>,
+        // Discord leaves the language on the first line of the code
+        language_line: bool,
+    },
+    Link(String),
+    Quote,
+    // Headings have a level, and paragraphs don't
+    Block(usize),
+    // Ordered lists have the number they start at
+    List(Option<usize>),
+    ListItem,
+    // Elements like spans and underlined text, which Markdown doesn't have, are left as their contents
+    Plain,
+}
+
+// An element that hasn't been closed yet, and where its contents start in the output
+struct Element {
+    name: String,
+    markup: Markup,
+    start: usize,
+}
+
+// Turns the HTML of a message into CommonMark
+// This handles the HTML that Discord messages are rendered to, along with the formatted messages of
+// Matrix, which are HTML to begin with, so it covers the elements that either of them can have
+// Custom emoji become their names, like they do in plain text
+pub fn markdown(html: &str) -> String {
+    let mut output = String::new();
+    let mut open: Vec<Element> = Vec::new();
+    let mut rest = html;
+    loop {
+        // The text up to the next tag, and the tag, if there's one
+        let (text, tag) = match rest.find('<').and_then(|start| Some((start, start + rest[start..].find('>')?))) {
+            Some((start, end)) => {
+                let parts = (&rest[..start], Some(&rest[start + 1..end]));
+                rest = &rest[end + 1..];
+                parts
+            }
+            None => (rest, None),
+        };
+        let text = html_escape::decode_html_entities(text);
+        let in_code = open.iter().any(|element| matches!(element.markup, Markup::Code | Markup::CodeBlock {..}));
+        if in_code {
+            output.push_str(&text);
+        } else {
+            // Whitespace that the browser would collapse isn't kept
+            let line_start = output.is_empty() || output.ends_with('\n');
+            output.push_str(&escape_markdown(&text.replace('\n', " "), line_start));
+        }
+        let tag = match tag {
+            Some(tag) => tag,
+            None => break,
+        };
+
+        let name = tag_name(tag);
+        if tag.starts_with('/') {
+            // Close the element, along with any that were left open inside it
+            if let Some(position) = open.iter().rposition(|element| element.name == name) {
+                while open.len() > position {
+                    let element = open.pop().unwrap();
+                    close(&mut output, element, &open);
+                }
+            }
+            continue;
+        }
+        let class = attribute(tag, "class").unwrap_or_default();
+        let classes: Vec<&str> = class.split_whitespace().collect();
+        let markup = match name.as_str() {
+            "br" if in_code => {
+                output.push('\n');
+                continue;
+            }
+            "br" => {
+                // A hard line break
+                output.push_str("\\\n");
+                continue;
+            }
+            "hr" => {
+                start_block(&mut output);
+                output.push_str("---\n\n");
+                continue;
+            }
+            "img" => {
+                if !in_code {
+                    output.push_str(&escape_markdown(&attribute(tag, "alt").unwrap_or_default(), false));
+                }
+                continue;
+            }
+            "strong" | "b" => Markup::Wrap("**"),
+            "em" | "i" => Markup::Wrap("*"),
+            "del" | "s" | "strike" => Markup::Wrap("~~"),
+            "span" if classes.contains(&"strikethrough") => Markup::Wrap("~~"),
+            "span" if classes.contains(&"inline_code") => Markup::Code,
+            "code" => match open.last_mut() {
+                // The language of a code block is on the code element inside it
+                Some(Element {markup: Markup::CodeBlock {language, ..}, ..}) => {
+                    *language = classes.iter().find_map(|class| class.strip_prefix("language-")).map(str::to_owned);
+                    Markup::Plain
+                }
+                _ => Markup::Code,
+            },
+            "pre" => Markup::CodeBlock {language: None, language_line: classes.contains(&"multiline_code")},
+            "a" => match attribute(tag, "href") {
+                Some(href) => Markup::Link(href),
+                None => Markup::Plain,
+            },
+            "blockquote" => Markup::Quote,
+            "p" => Markup::Block(0),
+            // Discord's role mentions are divs, but they're in the middle of the text
+            "div" if classes.contains(&"role") => Markup::Plain,
+            "div" => Markup::Block(0),
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => Markup::Block(name[1..].parse().unwrap_or(1)),
+            "ul" => Markup::List(None),
+            "ol" => Markup::List(Some(attribute(tag, "start").and_then(|start| start.parse().ok()).unwrap_or(1))),
+            "li" => Markup::ListItem,
+            _ => Markup::Plain,
+        };
+        if matches!((&markup, open.last()), (Markup::List(_), Some(Element {markup: Markup::ListItem, ..}))) {
+            // Lists in list items start on the next line, so that they don't make the list loose
+            let length = trim_breaks(&output).len();
+            output.truncate(length);
+            output.push('\n');
+        } else if matches!(markup, Markup::CodeBlock {..} | Markup::Quote | Markup::Block(_) | Markup::List(_)) {
+            start_block(&mut output);
+        }
+        // Void elements like `<img>` were handled above, so everything else gets closed
+        open.push(Element {name, markup, start: output.len()});
+    }
+    while let Some(element) = open.pop() {
+        close(&mut output, element, &open);
+    }
+    trim_breaks(&output).to_owned()
+}
+
+// Replaces the contents of the element in the output with their Markdown
+fn close(output: &mut String, element: Element, open: &[Element]) {
+    let contents = output.split_off(element.start);
+    let markdown = match element.markup {
+        Markup::Wrap(marker) => {
+            // Emphasis can't start or end with whitespace, so it goes outside the markers
+            let trimmed = contents.trim();
+            if trimmed.is_empty() {
+                contents
+            } else {
+                let start = contents.len() - contents.trim_start().len();
+                let end = contents.trim_end().len();
+                format!("{}{}{}{}{}", &contents[..start], marker, trimmed, marker, &contents[end..])
+            }
+        }
+        Markup::Code => {
+            let contents = contents.replace('\n', " ");
+            if contents.is_empty() {
+                contents
+            } else {
+                let fence = "`".repeat(longest_run(&contents, '`') + 1);
+                // Code that starts or ends with a backtick is padded, so the backtick isn't part of the fence
+                let padding = if contents.starts_with('`') || contents.ends_with('`') {" "} else {""};
+                format!("{0}{1}{2}{1}{0}", fence, padding, contents)
+            }
+        }
+        Markup::CodeBlock {mut language, language_line} => {
+            let mut code = contents.trim_matches('\n');
+            if let (true, Some((first, rest))) = (language_line, code.split_once('\n')) {
+                if !first.is_empty() && first.chars().all(|c| c.is_ascii_alphanumeric() || "+-#._".contains(c)) {
+                    language = Some(first.to_owned());
+                    code = rest;
+                }
+            }
+            let fence = "`".repeat(longest_run(code, '`').max(2) + 1);
+            format!("{0}{1}\n{2}\n{0}\n\n", fence, language.unwrap_or_default(), code)
+        }
+        Markup::Link(href) => {
+            if contents == escape_markdown(&href, false) && !href.contains(|c: char| c.is_whitespace() || c == '<' || c == '>') {
+                format!("<{}>", href)
+            } else {
+                format!("[{}]({})", contents, link_destination(&href))
+            }
+        }
+        Markup::Quote => {
+            let quoted: Vec<_> = trim_breaks(&contents)
+                .lines()
+                .map(|line| if line.is_empty() {String::from(">")} else {format!("> {}", line)})
+                .collect();
+            format!("{}\n\n", quoted.join("\n"))
+        }
+        Markup::Block(level) => {
+            let contents = trim_breaks(&contents);
+            if contents.is_empty() {
+                String::new()
+            } else if level > 0 {
+                // Headings can't have line breaks
+                format!("{} {}\n\n", "#".repeat(level), contents.replace("\\\n", " ").replace('\n', " "))
+            } else {
+                format!("{}\n\n", contents)
+            }
+        }
+        Markup::List(_) => format!("{}\n\n", trim_breaks(&contents)),
+        Markup::ListItem => {
+            // The number of an item is the number the list starts at plus the items before it
+            let marker = match open.last() {
+                Some(Element {markup: Markup::List(Some(first)), start, ..}) => {
+                    format!("{}. ", output[*start..].lines().filter(|line| !line.starts_with(' ') && !line.is_empty()).count() + first)
+                }
+                _ => String::from("- "),
+            };
+            let indent = " ".repeat(marker.len());
+            let mut item = marker;
+            for (i, line) in trim_breaks(&contents).lines().enumerate() {
+                if i > 0 {
+                    item.push('\n');
+                    if !line.is_empty() {
+                        item.push_str(&indent);
+                    }
+                }
+                item.push_str(line);
+            }
+            if !output.is_empty() && !output.ends_with('\n') {
+                output.push('\n');
+            }
+            item + "\n"
+        }
+        Markup::Plain => contents,
+    };
+    output.push_str(&markdown);
+}
+
+// Ends the paragraph before a block with a blank line
+fn start_block(output: &mut String) {
+    let length = trim_breaks(output).len();
+    output.truncate(length);
+    if !output.is_empty() {
+        output.push_str("\n\n");
+    }
+}
+
+// Removes the whitespace and line breaks at the end, which would be left hanging there
+fn trim_breaks(mut markdown: &str) -> &str {
+    loop {
+        let trimmed = markdown.trim_end();
+        match trimmed.strip_suffix('\\') {
+            // The backslash of a hard line break, rather than one that's escaped
+            Some(rest) if trimmed.len() < markdown.len() && !rest.ends_with('\\') => markdown = rest,
+            _ => return trimmed,
+        }
+    }
+}
+
+// Escapes the characters that would be read as Markdown
+// `line_start` is whether the text starts a line, where things like `#` and `1.` start blocks
+fn escape_markdown(text: &str, mut line_start: bool) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if "\\`*_[]<>~|".contains(c) || (line_start && "#-+=".contains(c)) {
+            escaped.push('\\');
+            escaped.push(c);
+        } else if c == '&' && chars.peek().is_some_and(|&next| next == '#' || next.is_ascii_alphanumeric()) {
+            // It could start an entity, like `&amp;`
+            escaped.push_str("\\&");
+        } else if line_start && c.is_ascii_digit() {
+            // Numbered list items
+            escaped.push(c);
+            while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+                escaped.push(digit);
+            }
+            if let Some(marker) = chars.next_if(|&c| c == '.' || c == ')') {
+                escaped.push('\\');
+                escaped.push(marker);
+            }
+        } else {
+            escaped.push(c);
+        }
+        line_start = (line_start && c == ' ') || c == '\n';
+    }
+    escaped
+}
+
+// The destination of a link to the URL, which can be a path relative to the document
+// The characters that would end the destination early are percent-encoded
+fn link_destination(url: &str) -> String {
+    let mut destination = String::with_capacity(url.len());
+    for c in url.chars() {
+        match c {
+            ' ' | '<' | '>' | '(' | ')' => destination.push_str(&format!("%{:02X}", c as u8)),
+            c if c.is_whitespace() => {}
+            c => destination.push(c),
+        }
+    }
+    destination
+}
+
+fn longest_run(text: &str, c: char) -> usize {
+    text.split(|other| other != c).map(str::len).max().unwrap_or_default()
+}
+
+// The name of the element in a tag like `p class="x"` or `/p`, in lower case
+fn tag_name(tag: &str) -> String {
+    tag.split(|c: char| c.is_whitespace() || c == '/')
+        .find(|part| !part.is_empty())
+        .unwrap_or_default()
+        .to_ascii_lowercase()
+}
+
+// The value of an attribute in a tag, which has to be in double quotes, the way the HTML of messages
+// has them
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    let pattern = format!("{}=\"", name);
+    let (start, _) = tag
+        .match_indices(&pattern)
+        .find(|(start, _)| tag[..*start].ends_with(char::is_whitespace))?;
+    let value = tag[start + pattern.len()..].split('"').next()?;
+    Some(html_escape::decode_html_entities(value).into_owned())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cheesecake::{format::ArchiveFormat, matrix::Matrix};
+    use discord_markdown::{convertor, parser};
+    use rusqlite::Connection;
+
+    // Renders Discord markdown the way `Discord::populate_messages` does
+    fn discord(content: &str) -> String {
+        markdown(&convertor::to_html_with_callbacks(
+            parser::parse(content),
+            |filename| (format!("disc/emoji/{}", filename), None),
+            |_| (String::from("alice"), None),
+            |_| (String::from("mods"), Some(String::from("#ff0000"))),
+            |_| (String::from("general"), None),
+        ))
+    }
+
+    // Renders a Matrix message with the body, and the formatted body if it has one, the way
+    // `Matrix::populate_messages` does
+    fn matrix(body: &str, formatted_body: Option<&str>) -> String {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE messages (id TEXT, message_type TEXT, name TEXT, avatar TEXT, color TEXT,
+                created_timestamp TEXT, edits TEXT, reference TEXT, content TEXT, formatted_content TEXT)"
+        ).unwrap();
+        conn.execute(
+            "INSERT INTO messages VALUES ('$e', 'm.text', 'alice', NULL, NULL, '2021-03-14T18:30:00+00:00', NULL, NULL, $1, $2)",
+            rusqlite::params![body, formatted_body],
+        ).unwrap();
+        let messages = Matrix.populate_messages(&conn, Path::new("refrigerator"), "backup", "{} TRUE", &[]).unwrap();
+        markdown(&messages[0].content)
+    }
+
+    #[test]
+    fn discord_formatting() {
+        assert_eq!(
+            discord("**bold** *it* ~~gone~~ `co`de` __under__ ||secret||"),
+            r"**bold** *it* ~~gone~~ `co`de\` under secret",
+        );
+        assert_eq!(discord("> quoted\nnot quoted"), "> quoted\n\nnot quoted");
+        assert_eq!(discord("see https://example.com and [x](y)"), r"see <https://example.com> and \[x\](y)");
+    }
+
+    #[test]
+    fn discord_mentions_and_emoji() {
+        assert_eq!(
+            discord("hi <@1> and <@&2> in <#3> <:cheese:123> <a:party:456>"),
+            "hi @alice and @mods in #general cheese party",
+        );
+    }
+
+    #[test]
+    fn discord_code_blocks() {
+        // Discord keeps the language on the first line
+        assert_eq!(
+            discord("```rust\nfn main() {\n    println!(\"*\");\n}\n```"),
+            "```rust\nfn main() {\n    println!(\"*\");\n}\n```",
+        );
+        assert_eq!(discord("```\nlet x = 1;\nx\n```"), "```\nlet x = 1;\nx\n```");
+    }
+
+    #[test]
+    fn discord_lists_stay_text() {
+        // Discord doesn't have lists or headings, so they're escaped
+        assert_eq!(
+            discord("- one\n- two\n1. first\n# not a heading"),
+            "\\- one\\\n\\- two\\\n1\\. first\\\n\\# not a heading",
+        );
+    }
+
+    #[test]
+    fn matrix_reply() {
+        assert_eq!(
+            markdown(concat!(
+                r#"<mx-reply><blockquote><a href="https://matrix.to/#/!r:x/$e">In reply to</a> "#,
+                r#"<a href="https://matrix.to/#/@alice:x">@alice:x</a><br>original</blockquote></mx-reply>the reply"#,
+            )),
+            "> [In reply to](https://matrix.to/#/!r:x/$e) [@alice:x](https://matrix.to/#/@alice:x)\\\n> original\n\nthe reply",
+        );
+    }
+
+    #[test]
+    fn matrix_lists() {
+        assert_eq!(
+            markdown("<ul><li>one</li><li>two<ul><li>nested</li></ul></li></ul><ol><li>first</li><li>second</li></ol>"),
+            "- one\n- two\n  - nested\n\n1. first\n2. second",
+        );
+        assert_eq!(
+            markdown(r#"<ol start="3"><li><p>para one</p><p>para two</p></li><li>next</li></ol>"#),
+            "3. para one\n\n   para two\n4. next",
+        );
+    }
+
+    #[test]
+    fn matrix_links() {
+        assert_eq!(
+            markdown(r#"<p>a <a href="https://example.com/a b(c)">link</a> and <a href="https://example.com">https://example.com</a></p>"#),
+            "a [link](https://example.com/a%20b%28c%29) and <https://example.com>",
+        );
+    }
+
+    #[test]
+    fn matrix_blocks() {
+        assert_eq!(
+            markdown("<h2>Title <em>x</em></h2><pre><code class=\"language-python\">print(&quot;```&quot;)\n</code></pre>"),
+            "## Title *x*\n\n````python\nprint(\"```\")\n````",
+        );
+        assert_eq!(
+            markdown("<p>1. not a list &amp; 2 * 3 &lt;b&gt;</p><hr><del>gone</del> <code>a`b</code>"),
+            "1\\. not a list & 2 \\* 3 \\<b\\>\n\n---\n\n~~gone~~ ``a`b``",
+        );
+        assert_eq!(markdown("<blockquote><p>one</p><p>two</p></blockquote>"), "> one\n>\n> two");
+    }
+
+    #[test]
+    fn matrix_plain_bodies() {
+        // Plain bodies are text, even when they look like HTML or Markdown
+        assert_eq!(matrix("<b>not bold</b> &amp; *not italic*", None), r"\<b\>not bold\</b\> \&amp; \*not italic\*");
+        assert_eq!(matrix("line one\n# line two", None), "line one\\\n\\# line two");
+        assert_eq!(matrix("<b>bold</b>", Some("<b>bold</b>")), "**bold**");
+    }
+
+    #[test]
+    fn escaping() {
+        assert_eq!(escape_markdown("# 1. a_b [c] <d> `e` ~f~ |g|", true), r"\# 1. a\_b \[c\] \<d\> \`e\` \~f\~ \|g\|");
+        // Only the start of a line can begin a block
+        assert_eq!(escape_markdown("# - + = 12) x", false), "# - + = 12) x");
+        assert_eq!(escape_markdown("  12) x\n- y\n3. z", true), "  12\\) x\n\\- y\n3\\. z");
+        assert_eq!(escape_markdown("&amp; & &#38;", false), r"\&amp; & \&#38;");
+    }
+}
@@ -0,0 +1,294 @@
+use chrono::prelude::*;
+use nom::{
+    branch::alt,
+    bytes::complete::{tag_no_case, take_till1, take_while},
+    character::complete::{char, digit1},
+    combinator::{all_consuming, map, map_opt, map_res, opt, rest, value},
+    sequence::{delimited, preceded, tuple},
+    IResult,
+};
+use super::error::{Error, Result};
+use super::format::ArchiveFormat;
+
+// Something a message can have, which every format stores differently
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Property {
+    Attachment,
+    Reaction,
+    Edited,
+}
+
+// One term of the filters that can be given along with a search query
+#[derive(Clone, Debug, PartialEq)]
+pub enum Filter {
+    // from:<name>
+    From(String),
+    // before:<date>, the start of the date
+    Before(NaiveDate),
+    // after:<date>, the end of the date
+    After(NaiveDate),
+    // during:<date>, the start and end of the date
+    During(NaiveDate, NaiveDate),
+    // has:attachment, has:reaction and is:edited
+    Has(Property),
+    // type:<message type>
+    Type(String),
+    // sql:<condition>, which takes up the rest of the filters
+    Sql(String),
+}
+
+// Parses filters like `from:"Jane Doe" during:2021-03 has:attachment`
+// Raw SQL filters are rejected unless `allow_sql` is set, because they can read anything in the backup
+pub fn parse(mut input: &str, allow_sql: bool) -> Result<Vec<Filter>> {
+    let mut filters = Vec::new();
+    loop {
+        input = input.trim_start();
+        if input.is_empty() {
+            return Ok(filters);
+        }
+        match filter(input) {
+            // A term has to end at a space, so that `has:attachments` isn't read as `has:attachment`
+            Ok((remaining, filter)) if remaining.is_empty() || remaining.starts_with(char::is_whitespace) => {
+                if matches!(filter, Filter::Sql(_)) && !allow_sql {
+                    return Err(Error::SqlFiltersDisabled);
+                }
+                filters.push(filter);
+                input = remaining;
+            }
+            _ => {
+                let term = input.split_whitespace().next().unwrap_or_default();
+                return Err(Error::InvalidFilter(term.to_owned()));
+            }
+        }
+    }
+}
+
+// Compiles the filters into an SQL condition on the messages table of the format
+// The values in the filters are appended to `params`, and the condition refers to them by number
+pub fn condition<F: ArchiveFormat + ?Sized>(format: &F, filters: &[Filter], params: &mut Vec<String>) -> String {
+    let mut param = |value: String| {
+        params.push(value);
+        format!("?{}", params.len())
+    };
+    // Filters on the same field match any of the values, like `from:alice from:bob`
+    let mut names = Vec::new();
+    let mut types = Vec::new();
+    let mut conditions = Vec::new();
+    for filter in filters {
+        match filter {
+            Filter::From(name) => names.push(format!("name = {} COLLATE NOCASE", param(name.to_owned()))),
+            Filter::Before(date) => conditions.push(format!("datetime(created_timestamp) < {}", param(midnight(date)))),
+            Filter::After(date) => conditions.push(format!("datetime(created_timestamp) >= {}", param(midnight(date)))),
+            Filter::During(start, end) => conditions.push(format!(
+                "datetime(created_timestamp) >= {} AND datetime(created_timestamp) < {}",
+                param(midnight(start)),
+                param(midnight(end)),
+            )),
+            Filter::Has(property) => conditions.push(format.property_condition(*property).to_owned()),
+            Filter::Type(message_type) => types.push(format!("message_type = {}", param(message_type.to_owned()))),
+            Filter::Sql(sql) => conditions.push(sql.to_owned()),
+        }
+    }
+    for alternatives in [names, types] {
+        if !alternatives.is_empty() {
+            conditions.push(alternatives.join(" OR "));
+        }
+    }
+    if conditions.is_empty() {
+        return String::from("TRUE");
+    }
+    conditions
+        .iter()
+        .map(|condition| format!("({})", condition))
+        .collect::<Vec<_>>()
+        .join(" AND ")
+}
+
+// Parses a local time like `2019-03-14 18:30`, or a date like the ones in filters, which is taken
+// to mean the midnight it starts at
+pub fn parse_time(input: &str) -> Result<NaiveDateTime> {
+    let input = input.trim();
+    for format in ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%dT%H:%M", "%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M"] {
+        if let Ok(time) = NaiveDateTime::parse_from_str(input, format) {
+            return Ok(time);
+        }
+    }
+    match all_consuming(date)(input) {
+        Ok((_, (start, _))) => Ok(start.and_hms(0, 0, 0)),
+        Err(_) => Err(Error::InvalidDate(input.to_owned())),
+    }
+}
+
+// Converts the local time to UTC, the way SQLite's datetime() returns it
+pub fn utc(time: &NaiveDateTime) -> String {
+    Local
+        .from_local_datetime(time)
+        .earliest()
+        // Times that are skipped when DST starts are taken as the UTC time instead
+        .unwrap_or_else(|| Local.from_utc_datetime(time))
+        .with_timezone(&Utc)
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string()
+}
+
+// The local midnight at the start of the date in UTC
+fn midnight(date: &NaiveDate) -> String {
+    utc(&date.and_hms(0, 0, 0))
+}
+
+fn filter(input: &str) -> IResult<&str, Filter> {
+    alt((
+        map(preceded(tag_no_case("from:"), text), |name| Filter::From(name.to_owned())),
+        map(preceded(tag_no_case("before:"), date), |(start, _)| Filter::Before(start)),
+        map(preceded(tag_no_case("after:"), date), |(_, end)| Filter::After(end)),
+        map(preceded(tag_no_case("during:"), date), |(start, end)| Filter::During(start, end)),
+        value(Filter::Has(Property::Attachment), tag_no_case("has:attachment")),
+        value(Filter::Has(Property::Reaction), tag_no_case("has:reaction")),
+        value(Filter::Has(Property::Edited), tag_no_case("is:edited")),
+        map(preceded(tag_no_case("type:"), text), |message_type| Filter::Type(message_type.to_owned())),
+        map(preceded(tag_no_case("sql:"), rest), |sql: &str| Filter::Sql(sql.trim().to_owned())),
+    ))(input)
+}
+
+// A word, or anything in double quotes
+fn text(input: &str) -> IResult<&str, &str> {
+    alt((
+        delimited(char('"'), take_while(|c| c != '"'), char('"')),
+        take_till1(char::is_whitespace),
+    ))(input)
+}
+
+// A year, month or day like `2021`, `2021-03` or `2021-03-14`, as the day it starts on and the day
+// after it ends
+fn date(input: &str) -> IResult<&str, (NaiveDate, NaiveDate)> {
+    let number = || map_res(digit1, |digits: &str| digits.parse::<u32>());
+    map_opt(
+        tuple((
+            map_res(digit1, |digits: &str| digits.parse::<i32>()),
+            opt(preceded(char('-'), number())),
+            opt(preceded(char('-'), number())),
+        )),
+        |(year, month, day)| match (month, day) {
+            (None, _) => Some((NaiveDate::from_ymd_opt(year, 1, 1)?, NaiveDate::from_ymd_opt(year + 1, 1, 1)?)),
+            (Some(month), None) => {
+                let start = NaiveDate::from_ymd_opt(year, month, 1)?;
+                let end = if month == 12 {
+                    NaiveDate::from_ymd_opt(year + 1, 1, 1)?
+                } else {
+                    NaiveDate::from_ymd_opt(year, month + 1, 1)?
+                };
+                Some((start, end))
+            }
+            (Some(month), Some(day)) => {
+                let start = NaiveDate::from_ymd_opt(year, month, day)?;
+                Some((start, start.succ_opt()?))
+            }
+        },
+    )(input)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::discord::Discord;
+
+    fn ymd(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd(year, month, day)
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(
+            parse(r#"from:"Jane Doe" from:bob type:"m.text""#, false).unwrap(),
+            vec![
+                Filter::From(String::from("Jane Doe")),
+                Filter::From(String::from("bob")),
+                Filter::Type(String::from("m.text")),
+            ],
+        );
+        assert_eq!(parse(r#"from:"""#, false).unwrap(), vec![Filter::From(String::new())]);
+        // A quote has to be closed
+        assert!(matches!(parse(r#"from:"Jane Doe"#, false), Err(Error::InvalidFilter(_))));
+    }
+
+    #[test]
+    fn whole_terms() {
+        assert_eq!(
+            parse("HAS:attachment has:reaction is:edited", false).unwrap(),
+            vec![
+                Filter::Has(Property::Attachment),
+                Filter::Has(Property::Reaction),
+                Filter::Has(Property::Edited),
+            ],
+        );
+        match parse("has:attachments", false) {
+            Err(Error::InvalidFilter(term)) => assert_eq!(term, "has:attachments"),
+            result => panic!("has:attachments was parsed as {:?}", result),
+        }
+        assert!(matches!(parse("from:alice nonsense", false), Err(Error::InvalidFilter(_))));
+    }
+
+    #[test]
+    fn date_ranges() {
+        assert_eq!(
+            parse("during:2021 during:2021-03 during:2021-12 during:2021-03-14", false).unwrap(),
+            vec![
+                Filter::During(ymd(2021, 1, 1), ymd(2022, 1, 1)),
+                Filter::During(ymd(2021, 3, 1), ymd(2021, 4, 1)),
+                Filter::During(ymd(2021, 12, 1), ymd(2022, 1, 1)),
+                Filter::During(ymd(2021, 3, 14), ymd(2021, 3, 15)),
+            ],
+        );
+        // Before is the start of the date, and after is the end of it
+        assert_eq!(
+            parse("before:2020-12 after:2020-12", false).unwrap(),
+            vec![Filter::Before(ymd(2020, 12, 1)), Filter::After(ymd(2021, 1, 1))],
+        );
+        assert_eq!(
+            parse("during:2020-12-31", false).unwrap(),
+            vec![Filter::During(ymd(2020, 12, 31), ymd(2021, 1, 1))],
+        );
+        for invalid in ["during:2021-13", "during:2021-02-30", "during:march"] {
+            assert!(matches!(parse(invalid, false), Err(Error::InvalidFilter(_))), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn sql_opt_in() {
+        assert!(matches!(parse("from:alice sql:1 = 1", false), Err(Error::SqlFiltersDisabled)));
+        // SQL takes up the rest of the filters
+        assert_eq!(
+            parse("from:alice sql: name = 'x' from:bob", true).unwrap(),
+            vec![Filter::From(String::from("alice")), Filter::Sql(String::from("name = 'x' from:bob"))],
+        );
+    }
+
+    #[test]
+    fn conditions() {
+        let mut params = Vec::new();
+        assert_eq!(condition(&Discord, &[], &mut params), "TRUE");
+        let filters = parse("from:alice during:2021-12 from:bob has:reaction", false).unwrap();
+        assert_eq!(
+            condition(&Discord, &filters, &mut params),
+            "(datetime(created_timestamp) >= ?2 AND datetime(created_timestamp) < ?3) AND \
+            (reactions IS NOT NULL AND reactions != '') AND \
+            (name = ?1 COLLATE NOCASE OR name = ?4 COLLATE NOCASE)",
+        );
+        assert_eq!(
+            params,
+            vec![
+                String::from("alice"),
+                midnight(&ymd(2021, 12, 1)),
+                midnight(&ymd(2022, 1, 1)),
+                String::from("bob"),
+            ],
+        );
+    }
+
+    #[test]
+    fn times() {
+        assert_eq!(parse_time(" 2021-03-14 18:30 ").unwrap(), ymd(2021, 3, 14).and_hms(18, 30, 0));
+        assert_eq!(parse_time("2021-12").unwrap(), ymd(2021, 12, 1).and_hms(0, 0, 0));
+        assert!(matches!(parse_time("yesterday"), Err(Error::InvalidDate(_))));
+    }
+}
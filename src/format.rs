@@ -0,0 +1,301 @@
+use rusqlite::{Connection, ToSql};
+use std::{collections::HashMap, path::Path};
+use super::{actions::{Message, SearchHit, SearchResults}, error::{Error, Result}, discord::Discord, matrix::Matrix, generic::{self, Generic}};
+use super::filter::{self, Filter, Property};
+
+// Private use characters that FTS5 puts around the matched terms in snippets, which get replaced
+// with <mark> tags once the snippet is escaped
+const MATCH_START: &str = "\u{E000}";
+const MATCH_END: &str = "\u{E001}";
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AssetType {
+    Avatar,
+    Attachment,
+    Emoji,
+}
+
+impl AssetType {
+    // Returns the name of the directory where assets of those type are stored
+    pub fn dir(&self) -> &str {
+        match self {
+            Self::Avatar => "avatars",
+            Self::Attachment => "attachments",
+            Self::Emoji => "emoji",
+        }
+    }
+
+    // The asset type that's stored in the directory
+    pub fn from_dir(dir: &str) -> Option<Self> {
+        match dir {
+            "avatars" => Some(Self::Avatar),
+            "attachments" => Some(Self::Attachment),
+            "emoji" => Some(Self::Emoji),
+            _ => None,
+        }
+    }
+}
+
+// Everything cheesecake needs to know about an archive format to render and search its backups
+// Each format module implements this, and new formats only have to be added to the registry
+pub trait ArchiveFormat: Send + Sync {
+    // The `type` of the backup, as it is written in info.json
+    fn name(&self) -> &'static str;
+
+    // Executes an SQL command and collects the messages into Vec<Message>
+    // `{}` in the query gets replaced with the `SELECT ... FROM messages WHERE` for the format
+    fn populate_messages(
+        &self,
+        conn: &Connection,
+        refrigerator: &Path,
+        backup_path: &str,
+        sql_query: &str,
+        params: &[&dyn ToSql],
+    ) -> Result<Vec<Message>>;
+
+    // Converts an asset path to a proper URL
+    fn url(&self, _refrigerator: &Path, backup_path: &str, asset_type: AssetType, asset_path: &str) -> String {
+        generic::url(backup_path, asset_type, asset_path)
+    }
+
+    // Returns the IDs and names of all the chats in the backup
+    fn chat_list(&self, conn: &Connection) -> Result<Vec<[String; 2]>> {
+        let mut chats = Vec::new();
+        let mut statement = conn.prepare("SELECT id, name FROM chats")?;
+        let mut rows = statement.query([])?;
+        while let Some(chat) = rows.next()? {
+            chats.push([
+                chat.get(0)?,
+                chat.get(1)?,
+            ]);
+        }
+        Ok(chats)
+    }
+
+    // An SQL condition for the messages that have the property, used by search filters
+    fn property_condition(&self, property: Property) -> &'static str;
+
+    // An SQL condition for the messages that populate_messages renders, so that searches don't
+    // count the ones it leaves out
+    fn shown_condition(&self) -> &'static str {
+        "TRUE"
+    }
+
+    // Full-text search using the `message_search` table, within a chat or across all of them
+    fn search(
+        &self,
+        conn: &Connection,
+        refrigerator: &Path,
+        backup_path: &str,
+        chat_id: Option<&str>,
+        search: &Search,
+    ) -> Result<SearchResults> {
+        let (total, matches) = self.search_matches(conn, chat_id, search)?;
+        let hits = self.render_matches(conn, refrigerator, backup_path, search.query, matches)?;
+        Ok(SearchResults { total, hits })
+    }
+
+    // Finds the page of messages that match the search without rendering them, along with the
+    // number of matches on all the pages
+    fn search_matches(
+        &self,
+        conn: &Connection,
+        chat_id: Option<&str>,
+        search: &Search,
+    ) -> Result<(usize, Vec<SearchMatch>)> {
+        let mut params = vec![search.query.to_owned()];
+        let mut condition = format!("({}) AND ", self.shown_condition());
+        if let Some(chat_id) = chat_id {
+            if !self.chat_list(conn)?.iter().any(|[id, _]| id == chat_id) {
+                return Err(Error::ChatNotFound(chat_id.to_owned()));
+            }
+            params.push(chat_id.to_owned());
+            condition.push_str("chat = ?2 AND ");
+        }
+        condition.push_str(&filter::condition(self, search.filters, &mut params));
+        let params: Vec<&dyn ToSql> = params.iter().map(|param| param as &dyn ToSql).collect();
+        let total = conn.query_row(
+            &format!(
+                "SELECT COUNT(*) FROM messages
+                WHERE messages.id IN (SELECT id FROM message_search WHERE message_search MATCH ?1) AND {}",
+                condition,
+            ),
+            &*params,
+            |row| row.get(0),
+        )?;
+
+        // `messages.id` has no index, so the matches drive the join and SQLite builds a temporary
+        // index to look the messages up in, instead of scanning `messages` for every match. The
+        // matches get renamed so that they can't be confused with the columns of `messages`
+        let mut statement = conn.prepare(&format!(
+            "SELECT messages.ROWID, chat, match_rank, datetime(created_timestamp)
+            FROM (SELECT id AS match_id, rank AS match_rank FROM message_search WHERE message_search MATCH ?1 ORDER BY rank)
+            CROSS JOIN messages ON messages.id = match_id
+            WHERE {} ORDER BY {} LIMIT {} OFFSET {}",
+            condition,
+            search.page.order.sql(),
+            search.page.limit,
+            search.page.offset,
+        ))?;
+        let mut rows = statement.query(&*params)?;
+        let mut page = Vec::new();
+        while let Some(row) = rows.next()? {
+            page.push(SearchMatch {
+                sequential_id: row.get(0)?,
+                chat_id: row.get(1)?,
+                rank: row.get(2)?,
+                timestamp: row.get(3)?,
+            });
+        }
+        Ok((total, page))
+    }
+
+    // Renders the matches of a search into hits with snippets
+    fn render_matches(
+        &self,
+        conn: &Connection,
+        refrigerator: &Path,
+        backup_path: &str,
+        query: &str,
+        matches: Vec<SearchMatch>,
+    ) -> Result<Vec<SearchHit>> {
+        let chats: HashMap<String, String> = self.chat_list(conn)?.into_iter().map(|[id, name]| (id, name)).collect();
+        let mut hits = Vec::new();
+        for search_match in matches {
+            // Some messages, like unsupported Matrix events, don't get rendered at all
+            let message = match self.populate_messages(conn, refrigerator, backup_path, "{} ROWID = $1", &[&search_match.sequential_id])?.pop() {
+                Some(message) => message,
+                None => continue,
+            };
+            hits.push(SearchHit {
+                chat_name: chats.get(&search_match.chat_id).cloned().unwrap_or_default(),
+                chat_id: search_match.chat_id,
+                snippet: None,
+                message,
+            });
+        }
+        let mut snippets = snippets(conn, query, &hits)?;
+        for hit in &mut hits {
+            hit.snippet = snippets.remove(&hit.message.message_id);
+        }
+        Ok(hits)
+    }
+}
+
+// A message that matched a search, before it's rendered
+pub struct SearchMatch {
+    pub sequential_id: u64,
+    pub chat_id: String,
+    // FTS5's rank for the match, where lower is better
+    pub rank: f64,
+    // When the message was sent, in UTC
+    pub timestamp: String,
+}
+
+// How search results are sorted
+#[derive(Clone, Copy, PartialEq)]
+pub enum SearchOrder {
+    // By FTS5's rank, best matches first
+    Relevance,
+    Newest,
+    Oldest,
+}
+
+impl SearchOrder {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "relevance" => Some(Self::Relevance),
+            "newest" => Some(Self::Newest),
+            "oldest" => Some(Self::Oldest),
+            _ => None,
+        }
+    }
+
+    fn sql(&self) -> &'static str {
+        match self {
+            Self::Relevance => "match_rank, messages.ROWID",
+            Self::Newest => "created_timestamp DESC, messages.ROWID DESC",
+            Self::Oldest => "created_timestamp, messages.ROWID",
+        }
+    }
+}
+
+// Which search results to get
+#[derive(Clone, Copy)]
+pub struct Page {
+    pub order: SearchOrder,
+    pub limit: usize,
+    pub offset: usize,
+}
+
+// Everything that decides the results of a search, other than where to search
+#[derive(Clone, Copy)]
+pub struct Search<'a> {
+    // An FTS5 query
+    pub query: &'a str,
+    pub filters: &'a [Filter],
+    pub page: Page,
+}
+
+// Gets the part of each hit around the matched terms, as HTML with the terms in <mark> tags, by
+// message ID
+// The snippets are made from the plain text in `message_search`, so they're escaped instead of
+// going through the markdown and HTML renderers of the formats
+fn snippets(conn: &Connection, query: &str, hits: &[SearchHit]) -> Result<HashMap<String, String>> {
+    let mut snippets = HashMap::new();
+    if hits.is_empty() {
+        return Ok(snippets);
+    }
+    let mut params = vec![query, MATCH_START, MATCH_END];
+    params.extend(hits.iter().map(|hit| hit.message.message_id.as_str()));
+    let ids = (4..=params.len()).map(|i| format!("?{}", i)).collect::<Vec<_>>().join(", ");
+    let mut statement = conn.prepare(&format!(
+        "SELECT CAST(id AS TEXT), snippet(message_search, -1, ?2, ?3, '…', 24) FROM message_search
+        WHERE message_search MATCH ?1 AND CAST(id AS TEXT) IN ({})",
+        ids,
+    ))?;
+    let mut rows = statement.query(rusqlite::params_from_iter(params))?;
+    while let Some(row) = rows.next()? {
+        let snippet: String = row.get(1)?;
+        let snippet = html_escape::encode_text(&snippet)
+            .replace(MATCH_START, "<mark>")
+            .replace(MATCH_END, "</mark>")
+            .replace('\n', "<br>");
+        snippets.insert(row.get(0)?, snippet);
+    }
+    Ok(snippets)
+}
+
+// Maps the `type` field in info.json to the format that can read the backup
+pub struct Registry {
+    formats: HashMap<&'static str, Box<dyn ArchiveFormat>>,
+}
+
+impl Registry {
+    // Creates a registry without any formats in it
+    pub fn empty() -> Self {
+        Registry { formats: HashMap::new() }
+    }
+
+    // Adds a format to the registry, replacing any existing format with the same name
+    pub fn register(&mut self, format: impl ArchiveFormat + 'static) -> &mut Self {
+        self.formats.insert(format.name(), Box::new(format));
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn ArchiveFormat> {
+        self.formats.get(name).map(|format| format.as_ref())
+    }
+}
+
+impl Default for Registry {
+    // A registry with all the formats that come with cheesecake
+    fn default() -> Self {
+        let mut registry = Registry::empty();
+        registry
+            .register(Discord)
+            .register(Matrix)
+            .register(Generic);
+        registry
+    }
+}
@@ -0,0 +1,174 @@
+use rusqlite::{Connection, DatabaseName, ErrorCode, OptionalExtension, Transaction, TransactionBehavior};
+use super::error::{Error, Result};
+
+// The tokenizer used when none is configured, which also matches letters without their accents
+pub const DEFAULT_TOKENIZER: &str = "unicode61 remove_diacritics 2";
+
+// How search indexes are built
+#[derive(Clone, Copy)]
+pub struct Options<'a> {
+    // The FTS5 tokenizer the index is built with
+    pub tokenizer: &'a str,
+    // Keep the index in the backup's database, so that it doesn't have to be built again every time
+    // the backup is searched. This changes backup.db, so it's only done when it's asked for
+    pub save: bool,
+}
+
+// Makes sure the `message_search` table exists and matches the messages, building it if it doesn't
+// An index that cheesecake saved in the backup is used if it was built with the tokenizer and the
+// triggers saved along with it haven't counted any changes to the messages since. One that came
+// with the backup, like the ones Recipes makes, is used with whatever tokenizer it has as long as
+// it has a row for every message. Otherwise the index is saved in the backup if `options.save` is
+// set and the backup can be written to, or built in the `temp` schema, which shadows the backup's
+// and lasts as long as the connection
+pub fn update(conn: &Connection, options: Options) -> Result<()> {
+    let tokenizer = options.tokenizer;
+    if has_table(conn, "temp", "message_search")? {
+        if meta(conn, "temp", "tokenizer")?.as_deref() == Some(tokenizer) {
+            return Ok(());
+        }
+        return build(conn, "temp", tokenizer);
+    }
+    match meta(conn, "main", "tokenizer")? {
+        Some(built_with) => {
+            if built_with == tokenizer && meta(conn, "main", "changes")?.as_deref() == Some("0") {
+                return Ok(());
+            }
+        }
+        None => {
+            if has_table(conn, "main", "message_search")? && indexes_every_message(conn)? {
+                return Ok(());
+            }
+        }
+    }
+    if options.save && !conn.is_readonly(DatabaseName::Main)? {
+        match build(conn, "main", tokenizer) {
+            // In-memory copies of decrypted backups are read-only without the connection knowing it
+            Err(Error::Database(rusqlite::Error::SqliteFailure(e, _))) if e.code == ErrorCode::ReadOnly => (),
+            result => return result,
+        }
+    }
+    build(conn, "temp", tokenizer)
+}
+
+fn has_table(conn: &Connection, schema: &str, table: &str) -> Result<bool> {
+    let found = conn
+        .query_row(
+            &format!("SELECT 1 FROM {}.sqlite_master WHERE type = 'table' AND name = $1", schema),
+            [table],
+            |_| Ok(()),
+        )
+        .optional()?;
+    Ok(found.is_some())
+}
+
+// Reads a value that was stored along with the index in the schema
+// Indexes that weren't built by cheesecake don't have any
+fn meta(conn: &Connection, schema: &str, key: &str) -> Result<Option<String>> {
+    if !has_table(conn, schema, "message_search")? || !has_table(conn, schema, "message_search_meta")? {
+        return Ok(None);
+    }
+    let value = conn
+        .query_row(
+            &format!("SELECT CAST(value AS TEXT) FROM {}.message_search_meta WHERE key = $1", schema),
+            [key],
+            |row| row.get(0),
+        )
+        .optional()?;
+    Ok(value)
+}
+
+// Whether the index in the backup has as many rows as there are messages, or messages with content
+fn indexes_every_message(conn: &Connection) -> Result<bool> {
+    let found = conn.query_row(
+        "SELECT (SELECT COUNT(*) FROM main.message_search) IN (
+            (SELECT COUNT(*) FROM main.messages),
+            (SELECT COUNT(*) FROM main.messages WHERE content IS NOT NULL)
+        )",
+        [],
+        |row| row.get(0),
+    )?;
+    Ok(found)
+}
+
+fn build(conn: &Connection, schema: &str, tokenizer: &str) -> Result<()> {
+    if schema == "temp" {
+        // Keep the index of decrypted backups off the disk
+        conn.pragma_update(None, "temp_store", "MEMORY")?;
+    }
+    // Rebuilding takes a while, so make sure nothing else writes to the backup in the meantime
+    // Read-only backups can't be locked for writing, and nothing else can write to them anyway
+    let behavior = if schema == "main" {TransactionBehavior::Immediate} else {TransactionBehavior::Deferred};
+    let transaction = Transaction::new_unchecked(conn, behavior)?;
+    transaction.execute_batch(&format!(
+        "DROP TABLE IF EXISTS {schema}.message_search;
+        CREATE VIRTUAL TABLE {schema}.message_search USING fts5(id UNINDEXED, content, tokenize = '{tokenizer}');
+        INSERT INTO {schema}.message_search (id, content) SELECT id, content FROM main.messages WHERE content IS NOT NULL;
+        CREATE TABLE IF NOT EXISTS {schema}.message_search_meta (key TEXT PRIMARY KEY, value TEXT);
+        DELETE FROM {schema}.message_search_meta;
+        INSERT INTO {schema}.message_search_meta (key, value) VALUES ('changes', '0');",
+        schema = schema,
+        tokenizer = tokenizer.replace('\'', "''"),
+    ))?;
+    transaction.execute(
+        &format!("INSERT INTO {}.message_search_meta (key, value) VALUES ('tokenizer', $1)", schema),
+        [tokenizer],
+    )?;
+    if schema == "main" {
+        // Anything that changes the messages, like another program, makes the index out of date
+        for (name, event) in [("insert", "INSERT"), ("update", "UPDATE OF id, content"), ("delete", "DELETE")] {
+            transaction.execute_batch(&format!(
+                "CREATE TRIGGER IF NOT EXISTS main.message_search_{} AFTER {} ON messages BEGIN
+                    UPDATE message_search_meta SET value = CAST(value AS INTEGER) + 1 WHERE key = 'changes';
+                END;",
+                name,
+                event,
+            ))?;
+        }
+    }
+    transaction.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPTIONS: Options = Options { tokenizer: DEFAULT_TOKENIZER, save: false };
+
+    // A backup with an index that came with it instead of being built by cheesecake
+    fn backup_with_index() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE messages (id TEXT, content TEXT);
+            INSERT INTO messages VALUES ('$0', 'cheesecake'), ('$1', NULL);
+            CREATE VIRTUAL TABLE message_search USING fts5(id UNINDEXED, content, tokenize = 'porter');
+            INSERT INTO message_search VALUES ('$0', 'cheesecake');"
+        ).unwrap();
+        conn
+    }
+
+    #[test]
+    fn reuse_backup_index() {
+        let conn = backup_with_index();
+        update(&conn, OPTIONS).unwrap();
+        assert!(!has_table(&conn, "temp", "message_search").unwrap());
+        // The porter tokenizer matches other forms of the word
+        let found: i64 = conn
+            .query_row("SELECT COUNT(*) FROM message_search WHERE message_search MATCH 'cheesecakes'", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(found, 1);
+    }
+
+    #[test]
+    fn rebuild_stale_backup_index() {
+        let conn = backup_with_index();
+        conn.execute("INSERT INTO messages VALUES ('$2', 'more cheesecake')", []).unwrap();
+        update(&conn, OPTIONS).unwrap();
+        assert!(has_table(&conn, "temp", "message_search").unwrap());
+        let found: i64 = conn
+            .query_row("SELECT COUNT(*) FROM message_search WHERE message_search MATCH 'cheesecake'", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(found, 2);
+    }
+}
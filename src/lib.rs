@@ -0,0 +1,19 @@
+// Reading cheesecake backups: finding them in the refrigerator, decrypting them, and listing,
+// reading and searching their chats
+// The web server and the command line tool in main.rs are built on this
+#[macro_use]
+extern crate serde_derive;
+
+pub mod actions;
+pub mod decrypted;
+pub mod encryption;
+pub mod error;
+pub mod filter;
+pub mod format;
+pub mod index;
+pub mod discord;
+pub mod matrix;
+pub mod generic;
+pub mod session;
+
+pub use error::{Error, Result};
@@ -4,56 +4,203 @@ extern crate rocket;
 extern crate serde_derive;
 
 use dotenv::dotenv;
-use rocket::{http::CookieJar, form::Form, response::Redirect, serde::json::Json, fs::FileServer, Config, State};
+use rocket::{http::{uri::{self, fmt::{FromUriParam, UriDisplay}}, ContentType, Cookie, CookieJar, Status}, fairing::AdHoc, form::{self, Form, FromFormField, ValueField}, request::FromParam, response::Redirect, serde::json::Json, fs::{FileServer, NamedFile}, config::SecretKey, Config, Either, State};
+use rocket::tokio::task::block_in_place;
 use rocket_dyn_templates::{tera::Tera, Template};
-use tempfile::{tempdir, NamedTempFile};
-use std::sync::Mutex;
+use tempfile::tempdir;
+use directories::ProjectDirs;
+use std::{env, fs, io::{self, Write}, net::IpAddr, ops::RangeInclusive, path::PathBuf, process, time::Duration};
 
+use cheesecake::{actions::{self, Refrigerator}, error::Error, filter, format::{AssetType, Page, Registry, Search, SearchOrder}, index};
+use commands::Arguments;
+use response::{ErrorResponse, Plaintext};
+use sessions::Sessions;
 use static_include::static_file;
 
-mod actions;
-mod discord;
-mod matrix;
-mod generic;
+// Rocket re-exports a uri macro for each route, which is unused outside the crate root
+#[allow(unused_imports)]
+mod api;
+mod commands;
+mod export;
+mod response;
+mod sessions;
+#[allow(unused_imports)]
 mod static_include;
 
+// Options that can be set in Rocket.toml or with ROCKET_ environment variables
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    // Keep decrypted backups in memory instead of in temporary files
+    decrypt_in_memory: bool,
+    // Seconds a browser session can go unused before its decrypted backups are locked, where 0 means
+    // they never are
+    session_timeout: u64,
+    // Accept `sql:` search filters, which can read anything in the backup
+    allow_sql_filters: bool,
+    // The FTS5 tokenizer that search indexes are built with
+    search_tokenizer: String,
+    // Save search indexes in the backups instead of building them every time a backup is opened
+    save_search_index: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            decrypt_in_memory: true,
+            session_timeout: 60 * 60,
+            allow_sql_filters: false,
+            search_tokenizer: String::from(index::DEFAULT_TOKENIZER),
+            save_search_index: false,
+        }
+    }
+}
+
+impl Settings {
+    pub fn index_options(&self) -> index::Options<'_> {
+        index::Options {
+            tokenizer: &self.search_tokenizer,
+            save: self.save_search_index,
+        }
+    }
+}
+
 #[derive(FromForm)]
 pub struct Password {
     password: String,
 }
 
+// The reader sends the backup and chat it's showing along with its requests, so that tabs showing
+// different chats don't mix them up. Otherwise, the ones in the cookies are used
+#[derive(FromForm)]
+pub struct BackupDetails {
+    backup: Option<String>,
+}
+
 #[derive(FromForm)]
 pub struct JumpDetails {
+    backup: Option<String>,
     chat_id: Option<String>,
     message_id: Option<String>,
 }
 
 #[derive(FromForm)]
 pub struct GetMessages {
+    backup: Option<String>,
+    chat: Option<String>,
     sequential_id: u64,
     position: String,
 }
 
+#[derive(FromForm)]
+pub struct DateDetails {
+    backup: Option<String>,
+    chat: Option<String>,
+    date: String,
+}
+
+#[derive(FromForm)]
+pub struct DensityDetails {
+    backup: Option<String>,
+    chat: Option<String>,
+    // Count the messages in each hour instead of each day
+    hourly: bool,
+}
+
+// Lets routes match only the asset directories of a backup
+pub struct AssetDir(AssetType);
+
+impl<'a> FromParam<'a> for AssetDir {
+    type Error = &'a str;
+    fn from_param(param: &'a str) -> Result<Self, Self::Error> {
+        AssetType::from_dir(param).map(AssetDir).ok_or(param)
+    }
+}
+
+// Lets routes match only backup paths that are a single directory name, since percent-encoded
+// slashes and dots are decoded before the path gets joined onto the refrigerator
+pub struct BackupDir<'a>(&'a str);
+
+impl<'a> FromParam<'a> for BackupDir<'a> {
+    type Error = &'a str;
+    fn from_param(param: &'a str) -> Result<Self, Self::Error> {
+        if actions::is_backup_path(param) {Ok(BackupDir(param))} else {Err(param)}
+    }
+}
+
+// So that `uri!` can link to routes that take one
+impl UriDisplay<uri::fmt::Path> for BackupDir<'_> {
+    fn fmt(&self, f: &mut uri::fmt::Formatter<'_, uri::fmt::Path>) -> std::fmt::Result {
+        f.write_value(self.0)
+    }
+}
+
+impl<'a> FromUriParam<uri::fmt::Path, &'a str> for BackupDir<'a> {
+    type Target = BackupDir<'a>;
+    fn from_uri_param(param: &'a str) -> Self::Target {
+        BackupDir(param)
+    }
+}
+
+// The order of search results in forms and query strings
+pub struct OrderField(SearchOrder);
+
+impl<'v> FromFormField<'v> for OrderField {
+    fn from_value(field: ValueField<'v>) -> form::Result<'v, Self> {
+        SearchOrder::from_name(field.value)
+            .map(OrderField)
+            .ok_or_else(|| form::Error::validation("expected relevance, newest or oldest").into())
+    }
+
+    // The best matches come first unless the order is specified
+    fn default() -> Option<Self> {
+        Some(OrderField(SearchOrder::Relevance))
+    }
+}
+
 #[derive(FromForm)]
 pub struct Query {
+    backup: Option<String>,
+    chat: Option<String>,
     string: String,
     filters: String,
+    // Search every chat in the backup instead of the current one
+    all_chats: bool,
+    order: OrderField,
+    #[field(default = 50, validate = range(1..=MAX_LIMIT as isize))]
+    limit: usize,
+    #[field(default = 0, validate = range(0..=MAX_OFFSET as isize))]
+    offset: usize,
 }
 
-pub struct DBFile {
-    backup_path: String,
-    file: Option<NamedTempFile>,
+#[derive(FromForm)]
+pub struct GlobalQuery {
+    string: String,
+    filters: String,
+    order: OrderField,
+    #[field(default = 50, validate = range(1..=MAX_LIMIT as isize))]
+    limit: usize,
+    #[field(default = 0, validate = range(0..=MAX_OFFSET as isize))]
+    offset: usize,
 }
 
-impl DBFile {
-    fn reset(&mut self) {
-        self.backup_path = String::new();
-        self.file = None;
-    }
+// The most search results, or messages from the API, that can be requested at once
+const MAX_LIMIT: usize = 200;
+// The furthest into the search results a page can start, so that the end of the page still fits in
+// SQLite's integers
+const MAX_OFFSET: usize = i64::MAX as usize - MAX_LIMIT;
+
+// The index page, with the message of the permalink that was opened if it's asking for the password
+// of the backup the message is in
+#[derive(Serialize)]
+struct IndexContext<'a> {
+    #[serde(flatten)]
+    selection_context: actions::SelectionContext<'a>,
+    message_id: Option<String>,
 }
 
-#[get("/")]
-fn get_index(db_file: &State<Mutex<DBFile>>, cookies: &CookieJar<'_>) -> Template {
+#[get("/?<message>")]
+fn get_index(refrigerator: &State<Refrigerator>, sessions: &State<Sessions>, cookies: &CookieJar<'_>, message: Option<String>) -> Template {
     let mut backup_path = "";
     let mut chat_id = "";
     if let Some(backup) = cookies.get("backup") {
@@ -64,97 +211,228 @@ fn get_index(db_file: &State<Mutex<DBFile>>, cookies: &CookieJar<'_>) -> Templat
     }
     Template::render(
         if cfg!(debug_assertions) {"index"} else {"index.html"},
-        actions::selection_context(&db_file.lock().unwrap(), backup_path, chat_id)
+        IndexContext {
+            selection_context: actions::selection_context(refrigerator, &sessions.get(cookies).lock().unwrap(), backup_path, chat_id),
+            message_id: message,
+        },
     )
 }
 
-#[get("/reader")]
-fn get_reader(db_file: &State<Mutex<DBFile>>, cookies: &CookieJar<'_>) -> Result<Template, Redirect> {
-    if let Some(backup) = cookies.get("backup") {
-        if backup.value() != db_file.lock().unwrap().backup_path {
-            // This is not a decrypted backup
-            db_file.lock().unwrap().reset();
-        }
-        if let Some(chat) = cookies.get("chat") {
-            return Ok(Template::render(
-                if cfg!(debug_assertions) {"reader"} else {"reader.html"},
-                actions::chat(&db_file.lock().unwrap(), backup.value(), chat.value()),
-            ));
+// Gets the value of a cookie that the request can't be handled without
+fn required_cookie(cookies: &CookieJar<'_>, name: &'static str) -> Result<String, Error> {
+    match cookies.get(name) {
+        Some(cookie) => Ok(cookie.value().to_owned()),
+        None => Err(Error::MissingParameter(name)),
+    }
+}
+
+// Gets the backup or chat that the request was sent for, falling back to the cookie
+fn current(value: &Option<String>, cookies: &CookieJar<'_>, name: &'static str) -> Result<String, Error> {
+    match value {
+        Some(value) => Ok(value.to_owned()),
+        None => required_cookie(cookies, name),
+    }
+}
+
+// Serves the assets of backups, decrypting them if they are encrypted
+#[get("/<backup>/<asset_dir>/<path..>", rank = 18)]
+async fn get_asset(refrigerator: &State<Refrigerator>, sessions: &State<Sessions>, cookies: &CookieJar<'_>, backup: BackupDir<'_>, asset_dir: AssetDir, path: PathBuf) -> Result<Either<Plaintext, NamedFile>, ErrorResponse> {
+    let BackupDir(backup) = backup;
+    let AssetDir(asset_type) = asset_dir;
+    let file = refrigerator.path.join(backup).join(asset_type.dir()).join(&path);
+    // Large attachments take a while to decrypt, so let the worker's other tasks run elsewhere
+    let asset = block_in_place(|| {
+        actions::decrypted_asset(refrigerator, &sessions.get(cookies).lock().unwrap(), backup, asset_type, &path)
+    })?;
+    match asset {
+        Some(asset) => {
+            let content_type = path
+                .extension()
+                .and_then(|extension| ContentType::from_extension(&extension.to_string_lossy()))
+                .unwrap_or(ContentType::Binary);
+            Ok(Either::Left(Plaintext(content_type, asset)))
         }
+        None => Ok(Either::Right(NamedFile::open(file).await?)),
     }
-    Err(Redirect::to("/"))
+}
+
+// Opens the chat that was last opened
+#[get("/reader")]
+fn get_reader(cookies: &CookieJar<'_>) -> Redirect {
+    match (cookies.get("backup"), cookies.get("chat")) {
+        (Some(backup), Some(chat)) => Redirect::to(uri!(get_chat(backup.value(), chat.value()))),
+        _ => Redirect::to("/"),
+    }
+}
+
+#[get("/b/<backup>/c/<chat>")]
+fn get_chat(refrigerator: &State<Refrigerator>, sessions: &State<Sessions>, cookies: &CookieJar<'_>, backup: BackupDir<'_>, chat: &str) -> Result<Either<Template, Redirect>, ErrorResponse> {
+    reader(refrigerator, sessions, cookies, backup.0, chat, None)
+}
+
+// Opens the chat with the messages around the message
+#[get("/b/<backup>/c/<chat>/m/<message>")]
+fn get_message(refrigerator: &State<Refrigerator>, sessions: &State<Sessions>, cookies: &CookieJar<'_>, backup: BackupDir<'_>, chat: &str, message: String) -> Result<Either<Template, Redirect>, ErrorResponse> {
+    reader(refrigerator, sessions, cookies, backup.0, chat, Some(message))
+}
+
+fn reader(refrigerator: &Refrigerator, sessions: &Sessions, cookies: &CookieJar<'_>, backup: &str, chat: &str, message: Option<String>) -> Result<Either<Template, Redirect>, ErrorResponse> {
+    let context = match actions::chat(refrigerator, &sessions.get(cookies).lock().unwrap(), backup, chat, &message) {
+        Ok(context) => context,
+        Err(Error::Locked) => {
+            // The index page asks for the password of the backup in the cookies, and then opens the
+            // chat at the message
+            cookies.add(Cookie::build("backup", backup.to_owned()).path("/").finish());
+            cookies.add(Cookie::build("chat", chat.to_owned()).path("/").finish());
+            return Ok(Either::Right(Redirect::to(uri!(get_index(message.as_deref())))));
+        }
+        Err(e) => return Err(e.into()),
+    };
+    Ok(Either::Left(Template::render(
+        if cfg!(debug_assertions) {"reader"} else {"reader.html"},
+        context,
+    )))
 }
 
 // POST requests
 
 #[post("/decrypt", data = "<password>")]
-fn post_decrypt(db_file: &State<Mutex<DBFile>>, cookies: &CookieJar<'_>, password: Form<Password>) -> Json<Vec<[String; 2]>> {
-    if let Some(backup) = cookies.get("backup") {
-        db_file.lock().unwrap().backup_path = backup.value().to_owned();
-        return Json(actions::decrypt(&mut db_file.lock().unwrap(), &password.password));
-    }
-    Json(Vec::new())
+fn post_decrypt(refrigerator: &State<Refrigerator>, sessions: &State<Sessions>, settings: &State<Settings>, cookies: &CookieJar<'_>, password: Form<Password>) -> Result<Json<Vec<[String; 2]>>, ErrorResponse> {
+    let backup = required_cookie(cookies, "backup")?;
+    let session = sessions.get(cookies);
+    // The session isn't kept locked while decrypting, so that the progress can be checked
+    let progress = session.lock().unwrap().start_decrypting(&backup);
+    // Decrypting large backups takes a while, so let the worker's other tasks run elsewhere
+    let result = block_in_place(|| {
+        actions::decrypt(refrigerator, &backup, &password.password, settings.decrypt_in_memory, &progress)
+    });
+    let mut session = session.lock().unwrap();
+    session.finish_decrypting(&backup);
+    let (decrypted, chats) = result?;
+    session.insert(&backup, decrypted);
+    Ok(Json(chats))
+}
+
+// Gets how many bytes of the current backup have been decrypted, and how many there are in total
+// This is null if the backup isn't being decrypted
+#[get("/decrypt/progress")]
+fn get_decrypt_progress(sessions: &State<Sessions>, cookies: &CookieJar<'_>) -> Result<Json<Option<[u64; 2]>>, ErrorResponse> {
+    let backup = required_cookie(cookies, "backup")?;
+    Ok(Json(sessions.get(cookies).lock().unwrap().progress(&backup)))
+}
+
+// Forgets the decrypted copy of the current backup
+#[post("/lock", data = "<info>")]
+fn post_lock(sessions: &State<Sessions>, cookies: &CookieJar<'_>, info: Form<BackupDetails>) -> Result<Status, ErrorResponse> {
+    let backup = current(&info.backup, cookies, "backup")?;
+    sessions.get(cookies).lock().unwrap().lock(&backup);
+    Ok(Status::NoContent)
 }
 
 #[post("/jump", data = "<info>")]
-fn post_jump<'a>(db_file: &State<Mutex<DBFile>>, cookies: &CookieJar<'_>, info: Form<JumpDetails>) -> Json<actions::ChatContext<'a>> {
-    if let Some(backup) = cookies.get("backup") {
-        if backup.value() != db_file.lock().unwrap().backup_path {
-            // This is not a decrypted backup
-            db_file.lock().unwrap().reset();
-        }
-        // If the chat ID is not specified, take the current chat
-        let chat_id = match &info.chat_id {
-            Some(chat_id) => chat_id,
-            None => match cookies.get("chat") {
-                Some(chat) => chat.value(),
-                None => return Json(actions::ChatContext::default()),
-            }
-        };
-        let messages = Json(actions::jump_chat(&db_file.lock().unwrap().file, backup.value(), chat_id, &info.message_id));
-        return messages;
-    }
-    Json(actions::ChatContext::default())
+fn post_jump<'a>(refrigerator: &State<Refrigerator>, sessions: &State<Sessions>, cookies: &CookieJar<'_>, info: Form<JumpDetails>) -> Result<Json<actions::ChatContext<'a>>, ErrorResponse> {
+    let backup = current(&info.backup, cookies, "backup")?;
+    // If the chat ID is not specified, take the current chat
+    let chat_id = match &info.chat_id {
+        Some(chat_id) => chat_id.to_owned(),
+        None => required_cookie(cookies, "chat")?,
+    };
+    let session = sessions.get(cookies);
+    let session = session.lock().unwrap();
+    let context = actions::jump_chat(refrigerator, session.decrypted(&backup), &backup, &chat_id, &info.message_id)?;
+    Ok(Json(context))
 }
 
 // Used for getting the messages around a specific message ID
 #[post("/messages", data = "<info>")]
-fn post_messages(db_file: &State<Mutex<DBFile>>, cookies: &CookieJar<'_>, info: Form<GetMessages>) -> Json<Vec<actions::Message>> {
-    if let Some(backup) = cookies.get("backup") {
-        if backup.value() != db_file.lock().unwrap().backup_path {
-            // This is not a decrypted backup
-            db_file.lock().unwrap().reset();
-        }
-        if let Some(chat) = cookies.get("chat") {
-            // The required cookies are present, so return the messages
-            let messages = actions::get_messages(
-                &db_file.lock().unwrap().file,
-                backup.value(),
-                chat.value(),
-                info.sequential_id,
-                &info.position,
-            );
-            return Json(messages);
-        }
-    }
-    // Return an empty vector by default
-    Json(Vec::new())
+fn post_messages(refrigerator: &State<Refrigerator>, sessions: &State<Sessions>, cookies: &CookieJar<'_>, info: Form<GetMessages>) -> Result<Json<Vec<actions::Message>>, ErrorResponse> {
+    let backup = current(&info.backup, cookies, "backup")?;
+    let chat = current(&info.chat, cookies, "chat")?;
+    let session = sessions.get(cookies);
+    let session = session.lock().unwrap();
+    // The required cookies are present, so return the messages
+    let messages = actions::get_messages(
+        refrigerator,
+        session.decrypted(&backup),
+        &backup,
+        &chat,
+        info.sequential_id,
+        &info.position,
+    )?;
+    Ok(Json(messages))
+}
+
+// Used for getting the messages around a date in the current chat
+#[post("/jump/date", data = "<info>")]
+fn post_jump_date(refrigerator: &State<Refrigerator>, sessions: &State<Sessions>, cookies: &CookieJar<'_>, info: Form<DateDetails>) -> Result<Json<actions::DateJump>, ErrorResponse> {
+    let backup = current(&info.backup, cookies, "backup")?;
+    let chat = current(&info.chat, cookies, "chat")?;
+    let time = filter::parse_time(&info.date)?;
+    let session = sessions.get(cookies);
+    let session = session.lock().unwrap();
+    let jump = actions::jump_to_date(refrigerator, session.decrypted(&backup), &backup, &chat, &time)?;
+    Ok(Json(jump))
+}
+
+// Used for getting the number of messages on each day of the current chat
+#[post("/density", data = "<info>")]
+fn post_density(refrigerator: &State<Refrigerator>, sessions: &State<Sessions>, cookies: &CookieJar<'_>, info: Form<DensityDetails>) -> Result<Json<Vec<(String, u64)>>, ErrorResponse> {
+    let backup = current(&info.backup, cookies, "backup")?;
+    let chat = current(&info.chat, cookies, "chat")?;
+    let session = sessions.get(cookies);
+    let session = session.lock().unwrap();
+    let density = actions::density(refrigerator, session.decrypted(&backup), &backup, &chat, info.hourly)?;
+    Ok(Json(density))
 }
 
 #[post("/search", data = "<query>")]
-fn post_search(db_file: &State<Mutex<DBFile>>, cookies: &CookieJar<'_>, query: Form<Query>) -> Json<Vec<actions::Message>> {
-    if let Some(backup) = cookies.get("backup") {
-        if backup.value() != db_file.lock().unwrap().backup_path {
-            // This is not a decrypted backup
-            db_file.lock().unwrap().reset();
-        }
-        if let Some(chat) = cookies.get("chat") {
-            // The required cookies are present, so return the search results
-            return Json(actions::search(&db_file.lock().unwrap().file, backup.value(), chat.value(), &query.string, &query.filters));
-        }
-    }
-    // Return an empty vector by default
-    Json(Vec::new())
+fn post_search(refrigerator: &State<Refrigerator>, settings: &State<Settings>, sessions: &State<Sessions>, cookies: &CookieJar<'_>, query: Form<Query>) -> Result<Json<actions::SearchResults>, ErrorResponse> {
+    let backup = current(&query.backup, cookies, "backup")?;
+    let filters = filter::parse(&query.filters, settings.allow_sql_filters)?;
+    let chat = if query.all_chats {
+        None
+    } else {
+        Some(current(&query.chat, cookies, "chat")?)
+    };
+    let session = sessions.get(cookies);
+    let session = session.lock().unwrap();
+    // The required cookies are present, so return the search results
+    let results = actions::search(
+        refrigerator,
+        session.decrypted(&backup),
+        &backup,
+        chat.as_deref(),
+        &Search {
+            query: &query.string,
+            filters: &filters,
+            page: Page {
+                order: query.order.0,
+                limit: query.limit,
+                offset: query.offset,
+            },
+        },
+        settings.index_options(),
+    )?;
+    Ok(Json(results))
+}
+
+// Searches every backup in the refrigerator that can be read in this session
+#[post("/search/global", data = "<query>")]
+fn post_global_search(refrigerator: &State<Refrigerator>, settings: &State<Settings>, sessions: &State<Sessions>, cookies: &CookieJar<'_>, query: Form<GlobalQuery>) -> Result<Json<actions::GlobalSearchResults>, ErrorResponse> {
+    let filters = filter::parse(&query.filters, settings.allow_sql_filters)?;
+    let search = Search {
+        query: &query.string,
+        filters: &filters,
+        page: Page {
+            order: query.order.0,
+            limit: query.limit,
+            offset: query.offset,
+        },
+    };
+    let session = sessions.get(cookies);
+    let session = session.lock().unwrap();
+    let results = actions::global_search(refrigerator, &session, &search, settings.index_options())?;
+    Ok(Json(results))
 }
 
 fn customize(tera: &mut Tera) {
@@ -164,12 +442,18 @@ fn customize(tera: &mut Tera) {
     ]).unwrap();
 }
 
-#[launch]
-fn rocket() -> _ {
-    // Read environment variables from .env
-    dotenv().ok();
+fn rocket(port: u16, address: Option<IpAddr>, refrigerator: Refrigerator) -> rocket::Rocket<rocket::Build> {
     // Configure rocket
-    let mut figment = Config::figment().merge(("port", 4000));
+    let mut figment = Config::figment().merge(("port", port));
+    if let Some(address) = address {
+        figment = figment.merge(("address", address));
+    }
+    if figment.extract_inner::<SecretKey>("secret_key").map_or(true, |key| key.is_zero()) {
+        // Sessions don't outlive the process, so a random key is fine if none was configured
+        let mut secret_key = [0; 32];
+        openssl::rand::rand_bytes(&mut secret_key).unwrap();
+        figment = figment.merge(("secret_key", base64::encode(secret_key)));
+    }
     let dir = tempdir().unwrap();
     if !cfg!(debug_assertions) {
         // Use a temp dir for templates in release builds because rocket needs one to start
@@ -183,16 +467,44 @@ fn rocket() -> _ {
             "/",
             routes![
                 get_index,
+                get_asset,
                 get_reader,
+                get_chat,
+                get_message,
                 post_decrypt,
+                get_decrypt_progress,
+                post_lock,
                 post_jump,
+                post_jump_date,
                 post_messages,
+                post_density,
                 post_search,
+                post_global_search,
                 static_file,
             ],
         )
-        .mount("/", FileServer::from(actions::refrigerator()).rank(19))
-        .manage(Mutex::new(DBFile {backup_path: String::new(), file: None}));
+        .mount("/api/v1", api::routes())
+        .mount("/", FileServer::from(&refrigerator.path).rank(19))
+        .manage(refrigerator)
+        .attach(AdHoc::config::<Settings>())
+        .attach(AdHoc::on_ignite("Sessions", |rocket| async {
+            let timeout = rocket.state::<Settings>().map_or(0, |settings| settings.session_timeout);
+            rocket.manage(Sessions::new((timeout > 0).then(|| Duration::from_secs(timeout))))
+        }))
+        .attach(AdHoc::on_liftoff("Session timeout", |rocket| Box::pin(async move {
+            // Lock backups in idle sessions even when nobody makes any requests
+            let sessions = rocket.state::<Sessions>().unwrap().clone();
+            let period = match sessions.timeout() {
+                Some(timeout) => timeout.clamp(Duration::from_secs(1), Duration::from_secs(60)),
+                None => return,
+            };
+            rocket::tokio::spawn(async move {
+                loop {
+                    rocket::tokio::time::sleep(period).await;
+                    sessions.sweep();
+                }
+            });
+        })));
     if cfg!(debug_assertions) {
         // We need to live reload templates in debug builds
         server.attach(Template::fairing())
@@ -203,3 +515,172 @@ fn rocket() -> _ {
             .attach(Template::custom(|engines| customize(&mut engines.tera)))
     }
 }
+
+const USAGE: &str = "Usage:
+    cheesecake [serve] [--port <port>] [--bind <address>]
+                                               Start the web server, on port 4000 unless another is given
+    cheesecake list [<backup>] [--json]        List the backups in the refrigerator, or the chats of a backup
+    cheesecake search <query> [--backup <backup>] [--chat <chat>] [--filters <filters>]
+                      [--order <order>] [--limit <limit>] [--json]
+                                               Search a backup or chat, or every unencrypted backup
+    cheesecake export <backup> [<chat>] [--format <format>] [--output <path>]
+                      [--since <time>] [--until <time>] [--search <query>] [--filters <filters>]
+                                               Export a chat, part of one or search results, or every chat
+                                               of a backup to HTML
+    cheesecake encrypt <backup> [--kdf <kdf>]  Encrypt a backup with a new password
+    cheesecake rekey <backup> [--kdf <kdf>]    Change the password of an encrypted backup
+    cheesecake decrypt <backup> <file>         Write a decrypted copy of a backup's database to a file
+    cheesecake encrypt-assets <backup>         Encrypt the avatars, attachments and emoji of an encrypted backup
+    cheesecake help                            Show this message
+
+Every command also takes --refrigerator <directory>, which is used instead of $REFRIGERATOR
+<backup> is the name of the backup's directory in the refrigerator
+<filters> are search filters like the ones in the reader, such as \"from:alice during:2021-03\"
+<order> is relevance (the default), newest or oldest
+<format> is json (the default), csv or transcript, which write the messages to the file or stdout,
+markdown, which writes them to the file and copies their attachments next to it, or html, which
+writes every message of the chats as pages that look like the reader to the directory
+<time> is a local time like 2021-03-14 18:30, or a date like 2021-03-14, 2021-03 or 2021, which means
+the time it starts; messages sent before --until are exported
+<kdf> is the key derivation function, one of argon2id (the default), scrypt or pbkdf2";
+
+fn usage() -> ! {
+    println!("{}", USAGE);
+    process::exit(2);
+}
+
+fn arguments<'a>(args: &[&'a str], positional: RangeInclusive<usize>, options: &[&str], flags: &[&str]) -> Arguments<'a> {
+    Arguments::parse(args, positional, options, flags).unwrap_or_else(|| usage())
+}
+
+// The settings for commands that don't start the web server, which are read the same way Rocket
+// reads them
+fn settings() -> Settings {
+    Config::figment().extract().unwrap_or_default()
+}
+
+async fn serve(args: &Arguments<'_>, refrigerator: Refrigerator) -> cheesecake::Result<()> {
+    let port = match args.option("--port") {
+        Some(port) => port
+            .parse()
+            .map_err(|_| Error::InvalidParameters(format!("`--port` expected a port number, not `{}`", port)))?,
+        None => 4000,
+    };
+    let address = match args.option("--bind") {
+        Some(address) => Some(
+            address
+                .parse()
+                .map_err(|_| Error::InvalidParameters(format!("`--bind` expected an IP address, not `{}`", address)))?,
+        ),
+        None => None,
+    };
+    // Rocket logs the details of why it failed to launch, if it does
+    if let Err(e) = rocket(port, address, refrigerator).launch().await {
+        println!("Error: {}", e);
+        process::exit(1);
+    }
+    Ok(())
+}
+
+// Finds the directory the backups are in
+// The path given with --refrigerator comes first, then the REFRIGERATOR environment variable, and
+// then a directory named `refrigerator` in the working directory. If that doesn't exist either, the
+// path is read from the config file, or asked for and stored there
+fn find_refrigerator(argument: Option<&str>) -> cheesecake::Result<PathBuf> {
+    let path = match argument {
+        Some(path) => PathBuf::from(path),
+        None => PathBuf::from(env::var_os("REFRIGERATOR").unwrap_or_else(|| "refrigerator".into())),
+    };
+    if path.is_dir() {
+        return Ok(path);
+    }
+    let project_dirs = ProjectDirs::from("", "", "cheesecake")
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "couldn't find the config directory"))?;
+    let config_file = project_dirs.config_dir().join("refrigerator_path");
+    let mut new_path = if config_file.exists() {
+        // If the path specified in the config file is a valid directory, use it
+        let new_path = PathBuf::from(fs::read_to_string(&config_file)?.trim());
+        if new_path.is_dir() {
+            return Ok(new_path);
+        }
+        new_path
+    } else {
+        prompt_path(&format!(
+            "Path `{}` is not valid. Either create the directory and restart cheesecake, or provide a new path here: ",
+            path.display(),
+        ))?
+    };
+    // Keep asking for a new path until we get a valid one
+    while !new_path.exists() {
+        new_path = prompt_path(&format!("Path `{}` is not valid. Please enter a valid path: ", new_path.display()))?;
+    }
+    // Store the new path in the config file
+    fs::create_dir_all(project_dirs.config_dir())?;
+    fs::write(&config_file, new_path.to_string_lossy().as_bytes())?;
+    Ok(new_path)
+}
+
+fn prompt_path(prompt: &str) -> cheesecake::Result<PathBuf> {
+    print!("{}", prompt);
+    io::stdout().flush()?;
+    let mut path = String::new();
+    if io::stdin().read_line(&mut path)? == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "no path was entered").into());
+    }
+    Ok(PathBuf::from(path.trim()))
+}
+
+#[rocket::main]
+async fn main() {
+    // Read environment variables from .env
+    dotenv().ok();
+    let args: Vec<String> = env::args().skip(1).collect();
+    let mut args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let mut argument = None;
+    if let Some(i) = args.iter().position(|&arg| arg == "--refrigerator") {
+        match args.get(i + 1) {
+            Some(&path) => argument = Some(path),
+            None => usage(),
+        }
+        args.drain(i..=i + 1);
+    }
+    // Asking for help shouldn't ask for the refrigerator
+    if let ["help" | "--help" | "-h"] = args[..] {
+        println!("{}", USAGE);
+        return;
+    }
+    // The formats are shared by the web server and every command
+    let refrigerator = match find_refrigerator(argument) {
+        Ok(path) => Refrigerator { path, registry: Registry::default() },
+        Err(e) => {
+            println!("Error: {}", e);
+            process::exit(1);
+        }
+    };
+    let result = match args[..] {
+        [] => serve(&Arguments::default(), refrigerator).await,
+        ["serve", ref args @ ..] => serve(&arguments(args, 0..=0, &["--port", "--bind"], &[]), refrigerator).await,
+        ["list", ref args @ ..] => commands::list(&refrigerator, &arguments(args, 0..=1, &[], &["--json"]), &settings()),
+        ["search", ref args @ ..] => commands::search(
+            &refrigerator,
+            &arguments(args, 1..=1, &["--backup", "--chat", "--filters", "--order", "--limit"], &["--json"]),
+            &settings(),
+        ),
+        ["export", ref args @ ..] => commands::export(
+            &refrigerator,
+            &arguments(args, 1..=2, &["--format", "--output", "--since", "--until", "--search", "--filters"], &[]),
+            &settings(),
+        ),
+        ["encrypt", backup] => commands::encrypt(&refrigerator.path, backup, None),
+        ["encrypt", backup, "--kdf", kdf] => commands::encrypt(&refrigerator.path, backup, Some(kdf)),
+        ["rekey", backup] => commands::rekey(&refrigerator.path, backup, None),
+        ["rekey", backup, "--kdf", kdf] => commands::rekey(&refrigerator.path, backup, Some(kdf)),
+        ["decrypt", backup, output] => commands::decrypt(&refrigerator.path, backup, output),
+        ["encrypt-assets", backup] => commands::encrypt_assets(&refrigerator.path, backup),
+        _ => usage(),
+    };
+    if let Err(e) = result {
+        println!("Error: {}", e);
+        process::exit(1);
+    }
+}
@@ -1,136 +1,208 @@
 use chrono::prelude::*;
 use rusqlite::{Connection, ToSql};
-use std::path::PathBuf;
+use std::path::Path;
+use super::error::{Error, Result};
 use super::actions::{Message, day_separator};
-use super::generic::{AssetType::*, url};
-
-// A function that executes an SQL command and collects the messages into Vec<Message>
-pub fn populate_messages<'a>(
-    database_path: &'a PathBuf,
-    backup_path: &'a str,
-    sql_query: &'a str,
-    params: &[&dyn ToSql],
-) -> Vec<Message> {
-    // Create a connection to the database
-    let conn = Connection::open(database_path).unwrap();
-    let mut messages: Vec<Message> = Vec::new();
-    let mut statement = conn.prepare(&sql_query.replace("{}", "SELECT ROWID,
-        id,
-        message_type,
-        name,
-        avatar,
-        color,
-        created_timestamp,
-        edits,
-        reference,
-        content,
-        formatted_content FROM messages WHERE")).unwrap();
-    let mut rows = statement.query(params).unwrap();
-
-    // Because the Message instance stores only a string representation of the time, we need this
-    // variable for easy comparison
-    let mut previous_timestamp = Local.timestamp(0, 0);
-
-    while let Some(row) = rows.next().unwrap() {
-        let message_type: String = row.get(2).unwrap();
-        let name = row.get(3).unwrap();
-        let avatar = match row.get::<_, String>(4) {
-            Ok(path) => url(backup_path, Avatar, &path),
-            Err(_) => String::from("/images/default.svg"),
-        };
-        let color = row.get(5).unwrap_or(String::from("#afafaf"));
-        // For checking if a day separator needs to be shown
-        let created_timestamp: DateTime<Local> = row.get(6).unwrap();
-        // For keeping track of whether the message should be displayed separately
-        let mut separate = true;
-
-        if messages.len() > 0 {
-            // Check if the message should be displayed separately
-            let previous = &messages[messages.len() - 1];
-            // We override the separate variable later on if the message is a reply
-            separate = !(previous.name == name && previous.avatar == avatar && previous.color == color && (created_timestamp - previous_timestamp).num_minutes() <= 5);
-            // Add a day separator if necessary
-            if previous_timestamp.date() != created_timestamp.date() {
-                messages.push(day_separator(created_timestamp));
-            }
-        }
+use super::filter::Property;
+use super::format::{ArchiveFormat, AssetType::*};
+
+pub struct Matrix;
+
+impl ArchiveFormat for Matrix {
+    fn name(&self) -> &'static str {
+        "matrix"
+    }
+
+    fn populate_messages(
+        &self,
+        conn: &Connection,
+        refrigerator: &Path,
+        backup_path: &str,
+        sql_query: &str,
+        params: &[&dyn ToSql],
+    ) -> Result<Vec<Message>> {
+        let mut messages: Vec<Message> = Vec::new();
+        let mut statement = conn.prepare(&sql_query.replace("{}", "SELECT ROWID,
+            id,
+            message_type,
+            name,
+            avatar,
+            color,
+            created_timestamp,
+            edits,
+            reference,
+            content,
+            formatted_content FROM messages WHERE"))?;
+        let mut rows = statement.query(params)?;
+
+        // Because the Message instance stores only a string representation of the time, we need this
+        // variable for easy comparison
+        let mut previous_timestamp = Local.timestamp(0, 0);
 
-        // Attachments
-        if message_type == "m.image" || message_type == "m.file" {
-            let file_type = if message_type == "m.image" {"image"} else {"unknown"}.to_string();
-            messages.push(Message {
-                sequential_id: row.get::<_, u64>(0).unwrap().to_string(),
-                message_id: row.get(1).unwrap(),
-                message_type: String::from("default"),
-                name,
-                avatar,
-                color,
-                created_timestamp: created_timestamp.format("%Y-%m-%d %H:%M").to_string(),
-                separate,
-                attachments: vec![(url(backup_path, Attachment, &row.get::<_, String>(9).unwrap()), file_type, false)],
-                ..Default::default()
-            });
-        } else if message_type == "m.room.redaction" {
-            messages.push(Message {
-                sequential_id: row.get::<_, u64>(0).unwrap().to_string(),
-                message_id: row.get(1).unwrap(),
-                message_type: String::from("redacted"),
-                name,
-                avatar,
-                color,
-                created_timestamp: created_timestamp.format("%Y-%m-%d %H:%M").to_string(),
-                separate: true,
-                ..Default::default()
-            });
-        } else if message_type == "m.text" {
-            let mut content = match row.get(10) {
-                Ok(formatted_content) => formatted_content,
-                Err(_) => row.get(9).unwrap_or(String::new()),
+        while let Some(row) = rows.next()? {
+            let message_type: String = row.get(2)?;
+            let name: String = row.get(3)?;
+            let avatar = match row.get::<_, String>(4) {
+                Ok(path) => self.url(refrigerator, backup_path, Avatar, &path),
+                Err(_) => String::from("/images/default.svg"),
             };
+            let color = row.get(5).unwrap_or(String::from("#afafaf"));
+            // For checking if a day separator needs to be shown
+            let created_timestamp: DateTime<Local> = row.get(6)?;
+            // For keeping track of whether the message should be displayed separately
+            let mut separate = true;
 
-            let mut edited_timestamp = None;
-            let mut edits_list = Vec::new();
-
-            // Edits
-            if let Ok(edits) = row.get::<_, String>(7) {
-                let edits: serde_json::Value = serde_json::from_str(&edits).unwrap();
-                edits_list.push([created_timestamp.format("%Y-%m-%d %H:%M").to_string(), content.clone()]);
-                for edit in edits.as_array().unwrap() {
-                    let edit = edit.as_array().unwrap();
-                    content = match edit[4].as_str() {
-                        Some(formatted_content) => formatted_content,
-                        None => edit[2].as_str().unwrap(),
-                    }.to_string();
-                    let timestamp = edit[0].as_i64().unwrap();
-                    let timestamp = Local.timestamp(timestamp / 1000, timestamp as u32 % 1000);
-                    edited_timestamp = Some(timestamp.format("%Y-%m-%d %H:%M").to_string());
-                    edits_list.push([timestamp.format("%Y-%m-%d %H:%M").to_string(), content.clone()]);
+            if !messages.is_empty() {
+                // Check if the message should be displayed separately
+                let previous = &messages[messages.len() - 1];
+                // We override the separate variable later on if the message is a reply
+                separate = !(previous.name == name && previous.avatar == avatar && previous.color == color && (created_timestamp - previous_timestamp).num_minutes() <= 5);
+                // Add a day separator if necessary
+                if previous_timestamp.date() != created_timestamp.date() {
+                    messages.push(day_separator(created_timestamp));
                 }
             }
 
-            let edits_list = if edits_list.is_empty() {
-                String::new()
-            } else {
-                serde_json::to_string(&edits_list).unwrap()
-            };
+            // Attachments
+            if message_type == "m.image" || message_type == "m.file" {
+                let file_type = if message_type == "m.image" {"image"} else {"unknown"}.to_string();
+                messages.push(Message {
+                    sequential_id: row.get::<_, u64>(0)?.to_string(),
+                    message_id: row.get(1)?,
+                    message_type: String::from("default"),
+                    name,
+                    avatar,
+                    color,
+                    created_timestamp: created_timestamp.format("%Y-%m-%d %H:%M").to_string(),
+                    separate,
+                    attachments: vec![(self.url(refrigerator, backup_path, Attachment, &row.get::<_, String>(9)?), file_type, false)],
+                    ..Default::default()
+                });
+            } else if message_type == "m.room.redaction" {
+                messages.push(Message {
+                    sequential_id: row.get::<_, u64>(0)?.to_string(),
+                    message_id: row.get(1)?,
+                    message_type: String::from("redacted"),
+                    name,
+                    avatar,
+                    color,
+                    created_timestamp: created_timestamp.format("%Y-%m-%d %H:%M").to_string(),
+                    separate: true,
+                    ..Default::default()
+                });
+            } else if message_type == "m.text" {
+                // Only formatted bodies are HTML, and plain ones are text even if they look like it
+                let mut content = match row.get::<_, String>(10) {
+                    Ok(formatted_content) => formatted_content,
+                    Err(_) => plain_html(&row.get::<_, String>(9).unwrap_or_default()),
+                };
+
+                let mut edited_timestamp = None;
+                let mut edits_list = Vec::new();
+
+                // Edits
+                if let Ok(edits) = row.get::<_, String>(7) {
+                    let invalid_edits = || Error::MalformedData(format!("invalid edits `{}`", edits));
+                    let edits: serde_json::Value = serde_json::from_str(&edits).map_err(|_| invalid_edits())?;
+                    edits_list.push([created_timestamp.format("%Y-%m-%d %H:%M").to_string(), content.clone()]);
+                    for edit in edits.as_array().ok_or_else(invalid_edits)? {
+                        let edit = edit.as_array().ok_or_else(invalid_edits)?;
+                        content = match edit.get(4).and_then(|e| e.as_str()) {
+                            Some(formatted_content) => formatted_content.to_owned(),
+                            None => plain_html(edit.get(2).and_then(|e| e.as_str()).ok_or_else(invalid_edits)?),
+                        };
+                        let timestamp = edit[0].as_i64().ok_or_else(invalid_edits)?;
+                        let timestamp = Local.timestamp(timestamp / 1000, timestamp as u32 % 1000);
+                        edited_timestamp = Some(timestamp.format("%Y-%m-%d %H:%M").to_string());
+                        edits_list.push([timestamp.format("%Y-%m-%d %H:%M").to_string(), content.clone()]);
+                    }
+                }
+
+                let edits_list = if edits_list.is_empty() {
+                    String::new()
+                } else {
+                    serde_json::to_string(&edits_list)?
+                };
+
+                messages.push(Message {
+                    sequential_id: row.get::<_, u64>(0)?.to_string(),
+                    message_id: row.get(1)?,
+                    message_type: String::from("default"),
+                    name,
+                    avatar,
+                    color,
+                    created_timestamp: created_timestamp.format("%Y-%m-%d %H:%M").to_string(),
+                    edited_timestamp,
+                    separate,
+                    content,
+                    edits_list,
+                    ..Default::default()
+                });
+            }
 
-            messages.push(Message {
-                sequential_id: row.get::<_, u64>(0).unwrap().to_string(),
-                message_id: row.get(1).unwrap(),
-                message_type: String::from("default"),
-                name,
-                avatar,
-                color,
-                created_timestamp: created_timestamp.format("%Y-%m-%d %H:%M").to_string(),
-                edited_timestamp,
-                separate,
-                content,
-                edits_list,
-                ..Default::default()
-            });
+            previous_timestamp = created_timestamp;
         }
+        Ok(messages)
+    }
+
+    fn shown_condition(&self) -> &'static str {
+        // Other events, like membership changes, aren't shown
+        "message_type IN ('m.text', 'm.image', 'm.file', 'm.room.redaction')"
+    }
+
+    fn property_condition(&self, property: Property) -> &'static str {
+        match property {
+            // Attachments are sent as messages of their own
+            Property::Attachment => "message_type IN ('m.image', 'm.file')",
+            Property::Reaction => "FALSE",
+            Property::Edited => "edits IS NOT NULL",
+        }
+    }
+}
+
+// The HTML that shows the plain text body of a message as it is
+fn plain_html(body: &str) -> String {
+    html_escape::encode_text(body).replace('\n', "<br>")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Reads the text messages with the bodies, formatted bodies and edits as the reader shows them
+    fn contents(messages: &[(&str, Option<&str>, Option<&str>)]) -> Vec<String> {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE messages (id TEXT, message_type TEXT, name TEXT, avatar TEXT, color TEXT,
+                created_timestamp TEXT, edits TEXT, reference TEXT, content TEXT, formatted_content TEXT)"
+        ).unwrap();
+        for (body, formatted_body, edits) in messages {
+            conn.execute(
+                "INSERT INTO messages VALUES ('$e', 'm.text', 'alice', NULL, NULL, '2021-03-14T18:30:00+00:00', $1, NULL, $2, $3)",
+                rusqlite::params![edits, body, formatted_body],
+            ).unwrap();
+        }
+        Matrix
+            .populate_messages(&conn, Path::new("refrigerator"), "backup", "{} TRUE", &[])
+            .unwrap()
+            .into_iter()
+            .map(|message| message.content)
+            .collect()
+    }
 
-        previous_timestamp = created_timestamp;
+    #[test]
+    fn plain_bodies_are_escaped() {
+        assert_eq!(
+            contents(&[
+                ("<script>alert(1)</script>\nhi", None, None),
+                ("<b>bold</b>", Some("<b>bold</b>"), None),
+                ("", None, Some(r#"[[1615746600000, "$edit", "<img src=x onerror=alert(2)>", null, null]]"#)),
+            ]),
+            [
+                "&lt;script&gt;alert(1)&lt;/script&gt;<br>hi",
+                "<b>bold</b>",
+                "&lt;img src=x onerror=alert(2)&gt;",
+            ],
+        );
     }
-    messages
 }
@@ -0,0 +1,78 @@
+use rocket::{http::{ContentType, Status}, request::Request, response::{self, Responder, Response}, serde::json::Json};
+use std::io;
+use zeroize::Zeroizing;
+use cheesecake::error::Error;
+
+// Errors are sent to the frontend as `{"error": "..."}` with an appropriate status code
+// The library doesn't know about HTTP, so routes wrap its errors in this
+#[derive(Debug)]
+pub struct ErrorResponse(pub Error);
+
+impl ErrorResponse {
+    pub fn status(&self) -> Status {
+        match &self.0 {
+            Error::Io(e) if e.kind() == io::ErrorKind::NotFound => Status::NotFound,
+            Error::MissingParameter(_) => Status::BadRequest,
+            Error::InvalidBackupPath(_) => Status::BadRequest,
+            Error::ChatNotFound(_) | Error::MessageNotFound(_) => Status::NotFound,
+            Error::Locked => Status::Unauthorized,
+            Error::WrongPassword => Status::Forbidden,
+            Error::NotEncrypted => Status::BadRequest,
+            Error::InvalidFilter(_) => Status::BadRequest,
+            Error::SqlFiltersDisabled => Status::Forbidden,
+            Error::InvalidDate(_) => Status::BadRequest,
+            Error::InvalidParameters(_) => Status::UnprocessableEntity,
+            _ => Status::InternalServerError,
+        }
+    }
+}
+
+impl From<Error> for ErrorResponse {
+    fn from(e: Error) -> Self {
+        ErrorResponse(e)
+    }
+}
+
+impl From<io::Error> for ErrorResponse {
+    fn from(e: io::Error) -> Self {
+        ErrorResponse(Error::Io(e))
+    }
+}
+
+impl From<serde_json::Error> for ErrorResponse {
+    fn from(e: serde_json::Error) -> Self {
+        ErrorResponse(Error::Json(e))
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ErrorResponse {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let status = self.status();
+        if status == Status::InternalServerError {
+            println!("Error while handling {}: {}", request.uri(), self.0);
+        }
+        (status, Json(serde_json::json!({ "error": self.0.to_string() }))).respond_to(request)
+    }
+}
+
+// A decrypted asset, which is sent without copying it out of the buffer that wipes it
+pub struct Plaintext(pub ContentType, pub Zeroizing<Vec<u8>>);
+
+// Lets the response body read straight from the zeroizing buffer
+struct PlaintextBody(Zeroizing<Vec<u8>>);
+
+impl AsRef<[u8]> for PlaintextBody {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl<'r> Responder<'r, 'static> for Plaintext {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let size = self.1.len();
+        Response::build()
+            .header(self.0)
+            .sized_body(size, io::Cursor::new(PlaintextBody(self.1)))
+            .ok()
+    }
+}
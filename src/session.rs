@@ -0,0 +1,63 @@
+use std::{collections::HashMap, sync::Arc, time::{Duration, Instant}};
+use super::decrypted::DecryptedBackup;
+use super::encryption::Progress;
+
+// The backups one user has decrypted, like a browser in the web server
+pub struct Session {
+    // Decrypted backups, by backup path
+    decrypted: HashMap<String, DecryptedBackup>,
+    // Backups that are being decrypted right now, by backup path
+    decrypting: HashMap<String, Arc<Progress>>,
+    last_active: Instant,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Session {
+            decrypted: HashMap::new(),
+            decrypting: HashMap::new(),
+            last_active: Instant::now(),
+        }
+    }
+}
+
+impl Session {
+    pub fn touch(&mut self) {
+        self.last_active = Instant::now();
+    }
+
+    // How long it has been since the session was last touched
+    pub fn idle(&self) -> Duration {
+        self.last_active.elapsed()
+    }
+
+    // Keeps track of the progress of decrypting the backup until finish_decrypting is called
+    pub fn start_decrypting(&mut self, backup_path: &str) -> Arc<Progress> {
+        let progress = Arc::new(Progress::default());
+        self.decrypting.insert(backup_path.to_owned(), progress.clone());
+        progress
+    }
+
+    pub fn finish_decrypting(&mut self, backup_path: &str) {
+        self.decrypting.remove(backup_path);
+    }
+
+    // Returns how far along decrypting the backup is, if it's being decrypted
+    pub fn progress(&self, backup_path: &str) -> Option<[u64; 2]> {
+        self.decrypting.get(backup_path).map(|progress| progress.get())
+    }
+
+    // Returns the decrypted copy of the backup, if it has been decrypted in this session
+    pub fn decrypted(&self, backup_path: &str) -> Option<&DecryptedBackup> {
+        self.decrypted.get(backup_path)
+    }
+
+    pub fn insert(&mut self, backup_path: &str, backup: DecryptedBackup) {
+        self.decrypted.insert(backup_path.to_owned(), backup);
+    }
+
+    // Drops the decrypted copy of the backup, so it has to be decrypted again before it can be read
+    pub fn lock(&mut self, backup_path: &str) {
+        self.decrypted.remove(backup_path);
+    }
+}
@@ -0,0 +1,78 @@
+use rocket::http::{Cookie, CookieJar, SameSite};
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::Duration};
+use cheesecake::session::Session;
+
+// Browsers are each given a session, identified by the private `session` cookie
+// Cloning this gives another handle to the same sessions
+#[derive(Clone)]
+pub struct Sessions {
+    sessions: Arc<Mutex<HashMap<String, Arc<Mutex<Session>>>>>,
+    // How long a session can go unused before its decrypted backups are locked, or None if sessions
+    // never time out
+    timeout: Option<Duration>,
+}
+
+impl Sessions {
+    pub fn new(timeout: Option<Duration>) -> Self {
+        Sessions {
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            timeout,
+        }
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    // Drops the sessions that timed out, which locks their decrypted backups
+    pub fn sweep(&self) {
+        let mut sessions = self.sessions.lock().unwrap();
+        self.retain_active(&mut sessions);
+    }
+
+    fn retain_active(&self, sessions: &mut HashMap<String, Arc<Mutex<Session>>>) {
+        let timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => return,
+        };
+        sessions.retain(|_, session| match session.try_lock() {
+            Ok(session) => session.idle() < timeout,
+            // The session is being used right now
+            Err(_) => true,
+        });
+    }
+
+    // Gets the session of the request, starting a new one if it doesn't have one yet
+    // Sessions that timed out are dropped here too, in case the request comes before the next sweep
+    pub fn get(&self, cookies: &CookieJar<'_>) -> Arc<Mutex<Session>> {
+        let id = match cookies.get_private("session") {
+            Some(cookie) => cookie.value().to_owned(),
+            None => {
+                let id = new_session_id();
+                cookies.add_private(
+                    Cookie::build("session", id.clone())
+                        .http_only(true)
+                        .same_site(SameSite::Strict)
+                        .finish()
+                );
+                id
+            }
+        };
+        let mut sessions = self.sessions.lock().unwrap();
+        self.retain_active(&mut sessions);
+        let session = sessions
+            .entry(id)
+            .or_insert_with(|| Arc::new(Mutex::new(Session::default())))
+            .clone();
+        // Don't keep every other session waiting while this one is busy
+        drop(sessions);
+        session.lock().unwrap().touch();
+        session
+    }
+}
+
+fn new_session_id() -> String {
+    let mut bytes = [0; 32];
+    openssl::rand::rand_bytes(&mut bytes).unwrap();
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
@@ -10,10 +10,10 @@ use rust_embed::RustEmbed;
 #[folder = "$CARGO_MANIFEST_DIR/static"]
 #[exclude = "*.scss"]
 #[exclude = "*.ts"]
-struct Assets;
+pub struct Assets;
 
 #[get("/<file..>", rank = 20)]
-pub fn static_file<'r>(file: PathBuf) -> Result<(ContentType, Cow<'static, [u8]>), Status> {
+pub fn static_file(file: PathBuf) -> Result<(ContentType, Cow<'static, [u8]>), Status> {
     let filename = file.display().to_string();
     let d = Assets::get(&filename).ok_or(Status::NotFound)?;
     let mut ext = file
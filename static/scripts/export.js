@@ -0,0 +1,116 @@
+var $messages = $("#messages");
+if (!location.hash) {
+    // Start at the newest messages, like the reader does
+    $messages.scrollTop($messages.prop("scrollHeight"));
+}
+function escape_html(text) {
+    return $("<span>").text(text).html();
+}
+// Opens the page with the message in the chat, at the message
+function open_message(chat, sequential_id) {
+    for (var i = 0; i < search_index.messages.length; i++) {
+        var message = search_index.messages[i];
+        if (message[0] === chat && message[2] === sequential_id) {
+            location.href = message[1] + "#" + message[2];
+            return;
+        }
+    }
+}
+$("#chat").on("change", function () {
+    location.href = $(this).val();
+});
+$(".spoiler").on("click", function () {
+    $(this).addClass("opened");
+});
+$(".message.reply .parent").on("click", function () {
+    open_message(exported.chat, $(this).attr("data-id"));
+});
+$(".content .timestamp.clickable").on("click", function () {
+    var edits_list = JSON.parse(this.dataset.editsList);
+    var $ul = $("#edits_dialog ul");
+    $ul.empty();
+    for (var i = edits_list.length - 1; i >= 0; i--) {
+        $ul.append("<li>" + edits_list[i][1] + " <span class=\"timestamp\">(" + edits_list[i][0] + ")</span></li>");
+    }
+    $(".overlay").show();
+    $("#edits_dialog").show("slow");
+});
+$("#edits_dialog button, .overlay").on("click", function () {
+    $("#edits_dialog").hide("slow", function () { return $(".overlay").hide(); });
+});
+$("#jump_date").on("change", function () {
+    var date = $(this).val();
+    if (date.length === 0)
+        return;
+    var last = null;
+    for (var i = 0; i < search_index.messages.length; i++) {
+        var message = search_index.messages[i];
+        if (message[0] !== exported.chat)
+            continue;
+        last = message;
+        // Timestamps start with the date, so they sort the same way as strings
+        if (message[4] >= date)
+            break;
+    }
+    if (last)
+        location.href = last[1] + "#" + last[2];
+});
+// How many search results are shown at once
+var SEARCH_PAGE_SIZE = 50;
+// Shows the messages that have every word of the query in them
+function search(query) {
+    var words = query.toLowerCase().split(/\s+/).filter(function (word) { return word.length; });
+    var all_chats = $("#search_scope").val() === "chats";
+    var hits = [];
+    var _loop_1 = function (i) {
+        var message = search_index.messages[i];
+        if (!all_chats && message[0] !== exported.chat)
+            return "continue";
+        var text = message[5].toLowerCase();
+        if (words.every(function (word) { return text.indexOf(word) !== -1; }))
+            hits.push(message);
+    };
+    for (var i = 0; i < search_index.messages.length; i++) {
+        _loop_1(i);
+    }
+    if ($("#search_order").val() !== "oldest")
+        hits.reverse();
+    var $results = $("#results");
+    $results.empty();
+    $results.append("<div id=\"info\">" + hits.length + " result" + (hits.length === 1 ? "" : "s") + "</div>");
+    show_hits(hits, 0, words, all_chats);
+    $results.scrollTop(0);
+}
+function show_hits(hits, offset, words, all_chats) {
+    var $results = $("#results");
+    $("#more_results").remove();
+    var _loop_2 = function (i) {
+        var message = hits[i];
+        var content = escape_html(message[5]);
+        for (var j = 0; j < words.length; j++) {
+            var word = escape_html(words[j]).replace(/[.*+?^${}()|[\]\\]/g, "\\$&");
+            content = content.replace(new RegExp(word, "gi"), "<mark>$&</mark>");
+        }
+        // Show where the message is from when searching more than one chat
+        var source = all_chats ? "#" + escape_html(search_index.chats[message[0]]) + " " : "";
+        var $hit = $("\n            <div class=\"message message_container\">\n                <div>\n                    <div class=\"title\">\n                        <span class=\"name\">" + escape_html(message[3]) + "</span>\n                        <span class=\"timestamp\">" + source + message[4] + "</span>\n                    </div>\n                    <div class=\"content\">" + content + "</div>\n                </div>\n            </div>\n        ");
+        $hit.on("click", function () { return location.href = message[1] + "#" + message[2]; });
+        $results.append($hit);
+    };
+    for (var i = offset; i < Math.min(offset + SEARCH_PAGE_SIZE, hits.length); i++) {
+        _loop_2(i);
+    }
+    if (offset + SEARCH_PAGE_SIZE < hits.length) {
+        $results.append("<button id=\"more_results\">more results</button>");
+        $("#more_results").on("click", function () { return show_hits(hits, offset + SEARCH_PAGE_SIZE, words, all_chats); });
+    }
+}
+$("#search form").on("submit", function (e) {
+    e.preventDefault();
+    var query = $("#query").val();
+    if (query.trim().length === 0) {
+        $("#results").empty();
+        return;
+    }
+    search(query);
+});
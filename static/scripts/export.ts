@@ -0,0 +1,133 @@
+// The script of exported chats, which works without the server
+// The chat and page being shown
+declare const exported;
+// Every exported message as [chat, page, sequential ID, name, timestamp, text], from search.js
+declare const search_index;
+
+const $messages = $("#messages");
+
+if (!location.hash) {
+    // Start at the newest messages, like the reader does
+    $messages.scrollTop($messages.prop("scrollHeight"));
+}
+
+function escape_html(text) {
+    return $("<span>").text(text).html();
+}
+
+// Opens the page with the message in the chat, at the message
+function open_message(chat, sequential_id) {
+    for (let i = 0; i < search_index.messages.length; i++) {
+        let message = search_index.messages[i];
+        if (message[0] === chat && message[2] === sequential_id) {
+            location.href = `${message[1]}#${message[2]}`;
+            return;
+        }
+    }
+}
+
+$("#chat").on("change", function() {
+    location.href = $(this).val() as string;
+});
+
+$(".spoiler").on("click", function() {
+    $(this).addClass("opened");
+});
+
+$(".message.reply .parent").on("click", function() {
+    open_message(exported.chat, $(this).attr("data-id"));
+});
+
+$(".content .timestamp.clickable").on("click", function() {
+    let edits_list = JSON.parse(this.dataset.editsList);
+    let $ul = $("#edits_dialog ul");
+    $ul.empty();
+    for (let i = edits_list.length - 1; i >= 0; i--) {
+        $ul.append(`<li>${edits_list[i][1]} <span class="timestamp">(${edits_list[i][0]})</span></li>`);
+    }
+    $(".overlay").show();
+    $("#edits_dialog").show("slow");
+});
+
+$("#edits_dialog button, .overlay").on("click", () => {
+    $("#edits_dialog").hide("slow", () => $(".overlay").hide());
+});
+
+$("#jump_date").on("change", function() {
+    const date = $(this).val() as string;
+    if (date.length === 0) return;
+    let last = null;
+    for (let i = 0; i < search_index.messages.length; i++) {
+        let message = search_index.messages[i];
+        if (message[0] !== exported.chat) continue;
+        last = message;
+        // Timestamps start with the date, so they sort the same way as strings
+        if (message[4] >= date) break;
+    }
+    if (last) location.href = `${last[1]}#${last[2]}`;
+});
+
+// How many search results are shown at once
+const SEARCH_PAGE_SIZE = 50;
+
+// Shows the messages that have every word of the query in them
+function search(query) {
+    const words = query.toLowerCase().split(/\s+/).filter(word => word.length);
+    const all_chats = $("#search_scope").val() === "chats";
+    let hits = [];
+    for (let i = 0; i < search_index.messages.length; i++) {
+        let message = search_index.messages[i];
+        if (!all_chats && message[0] !== exported.chat) continue;
+        let text = message[5].toLowerCase();
+        if (words.every(word => text.indexOf(word) !== -1)) hits.push(message);
+    }
+    if ($("#search_order").val() !== "oldest") hits.reverse();
+
+    const $results = $("#results");
+    $results.empty();
+    $results.append(`<div id="info">${hits.length} result${hits.length === 1 ? "" : "s"}</div>`);
+    show_hits(hits, 0, words, all_chats);
+    $results.scrollTop(0);
+}
+
+function show_hits(hits, offset, words, all_chats) {
+    const $results = $("#results");
+    $("#more_results").remove();
+    for (let i = offset; i < Math.min(offset + SEARCH_PAGE_SIZE, hits.length); i++) {
+        let message = hits[i];
+        let content = escape_html(message[5]);
+        for (let j = 0; j < words.length; j++) {
+            let word = escape_html(words[j]).replace(/[.*+?^${}()|[\]\\]/g, "\\$&");
+            content = content.replace(new RegExp(word, "gi"), "<mark>$&</mark>");
+        }
+        // Show where the message is from when searching more than one chat
+        let source = all_chats ? `#${escape_html(search_index.chats[message[0]])} ` : "";
+        let $hit = $(`
+            <div class="message message_container">
+                <div>
+                    <div class="title">
+                        <span class="name">${escape_html(message[3])}</span>
+                        <span class="timestamp">${source}${message[4]}</span>
+                    </div>
+                    <div class="content">${content}</div>
+                </div>
+            </div>
+        `);
+        $hit.on("click", () => location.href = `${message[1]}#${message[2]}`);
+        $results.append($hit);
+    }
+    if (offset + SEARCH_PAGE_SIZE < hits.length) {
+        $results.append(`<button id="more_results">more results</button>`);
+        $("#more_results").on("click", () => show_hits(hits, offset + SEARCH_PAGE_SIZE, words, all_chats));
+    }
+}
+
+$("#search form").on("submit", e => {
+    e.preventDefault();
+    const query = $("#query").val() as string;
+    if (query.trim().length === 0) {
+        $("#results").empty();
+        return;
+    }
+    search(query);
+});
@@ -12,6 +12,23 @@ function show_toast(message, duration) {
         }, duration);
     });
 }
+function error_message(xhr) {
+    return xhr.responseJSON && xhr.responseJSON.error ? xhr.responseJSON.error : xhr.statusText;
+}
+function set_cookie(name, value) {
+    document.cookie = name + "=" + value + "; path=/";
+}
+function permalink(backup, chat, message_id) {
+    if (message_id === void 0) { message_id = undefined; }
+    var url = "/b/" + encodeURIComponent(backup) + "/c/" + encodeURIComponent(chat);
+    if (message_id !== undefined)
+        url += "/m/" + encodeURIComponent(message_id);
+    return url;
+}
+$(document).ajaxError(function (_, xhr) {
+    if (xhr.status === 401)
+        location.href = "/";
+});
 function display_chats(backup) {
     $("#chat").prop("outerHTML", "<select id=\"chat\"></select>");
     $("label[for=\"chat\"]").text("Chat:");
@@ -25,23 +42,37 @@ $("#chat_switcher").trigger("reset").on("submit", function (e) {
     var $chat = $("#chat");
     if ($chat.is("input")) {
         if ($chat.val()) {
-            document.cookie = "backup=" + $("#backup").val();
+            set_cookie("backup", $("#backup").val());
             $("#chat_switcher button")
                 .prop("disabled", true)
                 .text("decrypting...");
             $backup.prop("disabled", true);
+            var progress = setInterval(function () {
+                $.get("/decrypt/progress").done(function (result) {
+                    if (result && result[1] && $("#chat_switcher button").prop("disabled")) {
+                        $("#chat_switcher button")
+                            .text("decrypting... " + Math.floor(result[0] / result[1] * 100) + "%");
+                    }
+                });
+            }, 500);
             $.post("/decrypt", { "password": $chat.val() }).done(function (result) {
                 console.log(result);
-                if (result.length) {
-                    chats = initial_chats;
-                    var backup = $backup.val();
+                var backup = $backup.val();
+                if (typeof linked_message !== "undefined" && linked_message && linked_message.backup === backup) {
+                    location.href = permalink(linked_message.backup, linked_message.chat, linked_message.message);
+                }
+                else if (result.length) {
                     chats[backup] = result;
                     display_chats(backup);
-                    show_toast("The backup will remain decrypted until you open a different backup or quit cheesecake", 5000);
+                    show_toast("The backup will remain decrypted until you lock it, leave it unused for a while, or quit cheesecake", 5000);
                 }
                 else {
-                    show_toast("No chats found. Is your password incorrect?");
+                    show_toast("No chats found in the backup");
                 }
+            }).fail(function (xhr) {
+                show_toast(xhr.status === 403 ? "Incorrect password" : error_message(xhr));
+            }).always(function () {
+                clearInterval(progress);
                 $backup.prop("disabled", false);
                 $("#chat_switcher button")
                     .text("proceed")
@@ -50,9 +81,9 @@ $("#chat_switcher").trigger("reset").on("submit", function (e) {
         }
     }
     else {
-        document.cookie = "backup=" + $("#backup").val();
-        document.cookie = "chat=" + $chat.val();
-        location.href = "/reader";
+        set_cookie("backup", $("#backup").val());
+        set_cookie("chat", $chat.val());
+        location.href = permalink($("#backup").val(), $chat.val());
     }
 });
 $backup.on("change", function () {
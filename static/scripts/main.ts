@@ -1,6 +1,7 @@
 declare const version;
-declare const initial_chats;
 declare let chats;
+// Only set on the index page
+declare const linked_message;
 
 // Check if cheesecake is up to date
 $.getJSON("https://api.github.com/repos/cubetastic33/cheesecake/releases/latest", result => {
@@ -19,6 +20,28 @@ function show_toast(message, duration = 2000) {
     });
 }
 
+// Gets the error message from a failed request
+function error_message(xhr) {
+    return xhr.responseJSON && xhr.responseJSON.error ? xhr.responseJSON.error : xhr.statusText;
+}
+
+// Sets a cookie for the whole site, since pages under /b/ would otherwise get their own
+function set_cookie(name, value) {
+    document.cookie = name + "=" + value + "; path=/";
+}
+
+// The URL of a chat, or of a message in it
+function permalink(backup, chat, message_id = undefined) {
+    let url = "/b/" + encodeURIComponent(backup) + "/c/" + encodeURIComponent(chat);
+    if (message_id !== undefined) url += "/m/" + encodeURIComponent(message_id);
+    return url;
+}
+
+// The backup was locked, so go back to the password prompt
+$(document).ajaxError((_, xhr) => {
+    if (xhr.status === 401) location.href = "/";
+});
+
 function display_chats(backup) {
     $("#chat").prop("outerHTML", `<select id="chat"></select>`);
     $("label[for=\"chat\"]").text("Chat:");
@@ -39,7 +62,7 @@ $("#chat_switcher").trigger("reset").on("submit", e =>  {
     if ($chat.is("input")) {
         // It's an encrypted backup
         if ($chat.val()) {
-            document.cookie = "backup=" + $("#backup").val();
+            set_cookie("backup", $("#backup").val());
             // If the password field is not empty
             $("#chat_switcher button")
                 .prop("disabled", true)
@@ -47,22 +70,37 @@ $("#chat_switcher").trigger("reset").on("submit", e =>  {
             // We read $backup.val() later so disable it now to ensure it stays the same
             $backup.prop("disabled", true);
 
+            // Show how much of the backup has been decrypted so far
+            let progress = setInterval(() => {
+                $.get("/decrypt/progress").done((result: [number, number] | null) => {
+                    if (result && result[1] && $("#chat_switcher button").prop("disabled")) {
+                        $("#chat_switcher button")
+                            .text(`decrypting... ${Math.floor(result[0] / result[1] * 100)}%`);
+                    }
+                });
+            }, 500);
+
             $.post("/decrypt", {"password": $chat.val()}).done(result => {
                 console.log(result);
 
-                if (result.length) {
-                    // Render the fetched chat names and reset previously decrypted chat names
+                let backup = $backup.val() as string;
+                if (typeof linked_message !== "undefined" && linked_message && linked_message.backup === backup) {
+                    // Go back to the message that was linked to
+                    location.href = permalink(linked_message.backup, linked_message.chat, linked_message.message);
+                } else if (result.length) {
+                    // Render the fetched chat names
                     // Updating `chats` will persist the chat names even after the backup selection
                     // is changed
-                    chats = initial_chats;
-                    let backup = $backup.val() as string;
                     chats[backup] = result;
                     display_chats(backup);
-                    show_toast("The backup will remain decrypted until you open a different backup or quit cheesecake", 5000);
+                    show_toast("The backup will remain decrypted until you lock it, leave it unused for a while, or quit cheesecake", 5000);
                 } else {
-                    show_toast("No chats found. Is your password incorrect?");
+                    show_toast("No chats found in the backup");
                 }
-
+            }).fail(xhr => {
+                show_toast(xhr.status === 403 ? "Incorrect password" : error_message(xhr));
+            }).always(() => {
+                clearInterval(progress);
                 // Enable inputs
                 $backup.prop("disabled", false);
                 $("#chat_switcher button")
@@ -71,9 +109,9 @@ $("#chat_switcher").trigger("reset").on("submit", e =>  {
             });
         }
     } else {
-        document.cookie = "backup=" + $("#backup").val();
-        document.cookie = "chat=" + $chat.val();
-        location.href = "/reader";
+        set_cookie("backup", $("#backup").val());
+        set_cookie("chat", $chat.val());
+        location.href = permalink($("#backup").val(), $chat.val());
     }
 });
 
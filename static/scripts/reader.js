@@ -7,23 +7,45 @@ function scroll_to_bottom() {
         $messages[0].scrollBy(0, $(this).prop("scrollHeight"));
     });
 }
-scroll_to_bottom();
+var target_message = target && document.querySelector("#messages [data-message-id=\"" + CSS.escape(target) + "\"]");
+if (target_message) {
+    target_message.scrollIntoView();
+}
+else {
+    if (target)
+        show_toast("That message can't be shown");
+    scroll_to_bottom();
+}
+function with_current(data) {
+    if (data === void 0) { data = {}; }
+    return $.extend({ backup: current.backup, chat: current.chat }, data);
+}
+function remember_current() {
+    set_cookie("backup", current.backup);
+    set_cookie("chat", current.chat);
+}
+function show_permalink(message_id) {
+    history.replaceState(null, "", permalink(current.backup, current.chat, message_id));
+}
+$(document).ajaxError(function (_, xhr) {
+    if (xhr.status === 401)
+        remember_current();
+});
+function escape_html(text) {
+    return $("<span>").text(text).html();
+}
 function jump(channel_id, message_id) {
     if (message_id === void 0) { message_id = undefined; }
     if (fetching)
         return;
     fetching = true;
-    $.post("/jump", {
-        chat_id: channel_id,
+    $.post("/jump", with_current({
+        chat_id: channel_id || current.chat,
         message_id: message_id,
-    }).done(function (result) {
-        if (!result.name.length) {
-            show_toast("channel not found");
-            fetching = false;
-            return;
-        }
+    })).done(function (result) {
         if (channel_id) {
-            document.cookie = "chat=" + channel_id;
+            current.chat = channel_id;
+            remember_current();
             $("#chat").val(channel_id);
             $("#chat_header .name").text(result.name);
             $("#chat_header .topic").text(result.topic);
@@ -31,14 +53,26 @@ function jump(channel_id, message_id) {
         $messages.empty();
         display_messages(result.messages, true);
         if (message_id) {
-            document.querySelector("[data-message-id=\"" + message_id + "\"]").scrollIntoView();
+            var target_message_1 = document.querySelector("#messages [data-message-id=\"" + CSS.escape(message_id) + "\"]");
+            if (target_message_1) {
+                target_message_1.scrollIntoView();
+            }
+            else {
+                show_toast("That message can't be shown");
+                scroll_to_bottom();
+            }
+            show_permalink(message_id);
         }
         else {
+            show_permalink(undefined);
             $messages.append("<div id=\"bottom_loading\">Loading...</div>");
             $("#bottom_loading").hide();
             scroll_to_bottom();
         }
         fetching = false;
+    }).fail(function (xhr) {
+        show_toast(error_message(xhr));
+        fetching = false;
     });
 }
 function init_handlers() {
@@ -103,7 +137,7 @@ function display_messages(messages, ascending) {
                 var attachment_icon = "<svg xmlns=\"http://www.w3.org/2000/svg\" height=\"24\" viewBox=\"0 0 24 24\" width=\"24\"><path d=\"M0 0h24v24H0z\" fill=\"none\"/><path d=\"M14 2H6c-1.1 0-1.99.9-1.99 2L4 20c0 1.1.89 2 1.99 2H18c1.1 0 2-.9 2-2V8l-6-6zm2 16H8v-2h8v2zm0-4H8v-2h8v2zm-3-5V3.5L18.5 9H13z\"/></svg>";
                 parent_1 = "<div class=\"parent\" data-id=\"" + message.reference[0] + "\">\n                    <img src=\"" + message.reference[2] + "\" alt=\"pfp\" class=\"avatar\">\n                    <span class=\"name\" style=\"color: " + message.reference[3] + "\">" + message.reference[1] + "</span>\n                    <span class=\"content\">" + message.reference[4] + "</span>\n                    " + (message.reference[5] ? attachment_icon : "") + "\n                </div>\n                <div class=\"message_container\">";
             }
-            html = "<div id=\"" + message.sequential_id + "\" data-message-id=\"" + message.message_id + "\" class=\"message " + (message.reference ? "reply" : "message_container") + "\">\n                " + parent_1 + "<img src=\"" + message.avatar + "\" alt=\"pfp\" class=\"avatar\">\n                <div>\n                    <div class=\"title\">\n                        <span class=\"name\" style=\"color: " + message.color + "\">" + message.name + "</span>\n                        <span class=\"timestamp\">" + message.created_timestamp + "</span>\n                        " + (message.bot ? "<span class=\"bot\">BOT</span>" : "") + "\n                    </div>\n                    <div class=\"content\" title=\"" + message.created_timestamp + "\">" + message.content + "</div>\n                </div>" + (message.reference ? "</div>" : "") + "\n            </div>";
+            html = "<div id=\"" + message.sequential_id + "\" data-message-id=\"" + message.message_id + "\" class=\"message " + (message.reference ? "reply" : "message_container") + "\">\n                " + parent_1 + "<img src=\"" + message.avatar + "\" alt=\"pfp\" class=\"avatar\">\n                <div>\n                    <div class=\"title\">\n                        <span class=\"name\" style=\"color: " + message.color + "\">" + message.name + "</span>\n                        <a class=\"timestamp\" href=\"" + permalink(current.backup, current.chat, message.message_id) + "\">" + message.created_timestamp + "</a>\n                        " + (message.bot ? "<span class=\"bot\">BOT</span>" : "") + "\n                    </div>\n                    <div class=\"content\" title=\"" + message.created_timestamp + "\">" + message.content + "</div>\n                </div>" + (message.reference ? "</div>" : "") + "\n            </div>";
         }
         else if (message.message_type === "new_member") {
             html = "<div id=\"" + message.sequential_id + "\" data-message-id=\"" + message.message_id + "\" class=\"message message_container\">\n                <div class=\"spacer\">\n                    <svg xmlns=\"http://www.w3.org/2000/svg\" enable-background=\"new 0 0 24 24\" height=\"24\" viewBox=\"0 0 24 24\" width=\"24\"><rect fill=\"none\" height=\"24\" width=\"24\"/><path d=\"M15,5l-1.41,1.41L18.17,11H2V13h16.17l-4.59,4.59L15,19l7-7L15,5z\"/></svg>\n                </div>\n                <div>\n                    <div class=\"content\" title=\"" + message.created_timestamp + "\" data-bot=\"" + message.bot + "\">" + message.content + "</div>\n                </div>\n            </div>";
@@ -173,7 +207,7 @@ $messages.on("scroll", function () {
         fetching = true;
         $messages.prepend("<div id=\"top_loading\">Loading...</div>");
         var reference_message_1 = $("#messages .message")[0].id;
-        $.post("/messages", { sequential_id: reference_message_1, position: "above" }).done(function (result) {
+        $.post("/messages", with_current({ sequential_id: reference_message_1, position: "above" })).done(function (result) {
             var $top_loading = $("#top_loading");
             if (result.length === 0) {
                 fetching = false;
@@ -188,12 +222,16 @@ $messages.on("scroll", function () {
             document.getElementById(reference_message_1).scrollIntoView();
             $top_loading.remove();
             fetching = false;
+        }).fail(function (xhr) {
+            show_toast(error_message(xhr));
+            $("#top_loading").remove();
+            fetching = false;
         });
     }
     else if ($messages.scrollTop() + $messages.prop("offsetHeight") >= $messages.prop("scrollHeight") && $("#bottom_loading").length === 0 && !fetching) {
         fetching = true;
         $messages.append("<div id=\"bottom_loading\">Loading...</div>");
-        $.post("/messages", { sequential_id: $("#messages .message:last")[0].id, position: "below" }).done(function (result) {
+        $.post("/messages", with_current({ sequential_id: $("#messages .message:last")[0].id, position: "below" })).done(function (result) {
             var $bottom_loading = $("#bottom_loading");
             if (result.length === 0) {
                 fetching = false;
@@ -207,63 +245,151 @@ $messages.on("scroll", function () {
             }
             $bottom_loading.remove();
             fetching = false;
+        }).fail(function (xhr) {
+            show_toast(error_message(xhr));
+            $("#bottom_loading").remove();
+            fetching = false;
         });
     }
 });
+$("#lock_button").on("click", function () {
+    $.post("/lock", with_current()).done(function () {
+        remember_current();
+        location.href = "/";
+    }).fail(function (xhr) { return show_toast(error_message(xhr)); });
+});
 $(window).on("keydown", function (e) {
     if (e.ctrlKey && e.key === "f") {
         e.preventDefault();
         $("#query").trigger("focus");
     }
 });
-$("#search form").on("submit", function (e) {
-    e.preventDefault();
+var SEARCH_PAGE_SIZE = 50;
+function search(query, offset) {
     var $search_button = $("#search form button");
     var $results = $("#results");
-    var query = $("#query").val();
-    $search_button.prop("disabled", true);
-    if (query.length === 0) {
-        $results.empty();
-        $search_button.prop("disabled", false);
-        return;
-    }
-    $.post("/search", { string: query, filters: $("#filters").val() }).done(function (result) {
-        $results.empty();
-        for (var i = 0; i < result.length; i++) {
-            var message = result[i];
-            if (message.message_type !== "default") {
-                continue;
+    var scope = $("#search_scope").val();
+    var data = {
+        string: query,
+        filters: $("#filters").val(),
+        order: $("#search_order").val(),
+        limit: SEARCH_PAGE_SIZE,
+        offset: offset,
+    };
+    var request = scope === "backups" ? $.post("/search/global", data) : $.post("/search", with_current($.extend({ all_chats: scope === "chats" }, data)));
+    request.done(function (result) {
+        var hits = result.hits;
+        if (offset === 0) {
+            $results.empty();
+            var info = result.total + " result" + (result.total === 1 ? "" : "s");
+            if (scope === "backups" && result.failed.length) {
+                info += " (couldn't search " + result.failed.map(function (backup) { return escape_html(backup[0]); }).join(", ") + ")";
+            }
+            $results.append("<div id=\"info\">" + info + "</div>");
+        }
+        $("#more_results").remove();
+        for (var i = 0; i < hits.length; i++) {
+            var message = hits[i].message;
+            var source = "";
+            if (scope === "backups") {
+                source = escape_html(hits[i].backup_name) + " #" + escape_html(hits[i].chat_name) + " ";
             }
-            $results.prepend("\n                <div class=\"message message_container\" data-id=\"" + message.sequential_id + "\">\n                    <img src=\"" + message.avatar + "\" alt=\"pfp\" class=\"avatar\">\n                    <div>\n                        <div class=\"title\">\n                            <span class=\"name\" style=\"color: " + message.color + "\">" + message.name + "</span>\n                            <span class=\"timestamp\">" + message.created_timestamp + "</span>\n                        </div>\n                        <div class=\"content\" data-bot=\"" + message.bot + "\">" + message.content + "</div>\n                    </div>\n                </div>\n            ");
+            else if (scope === "chats") {
+                source = "#" + escape_html(hits[i].chat_name) + " ";
+            }
+            var $hit = $("\n                <div class=\"message message_container\" data-id=\"" + message.sequential_id + "\">\n                    <img src=\"" + message.avatar + "\" alt=\"pfp\" class=\"avatar\">\n                    <div>\n                        <div class=\"title\">\n                            <span class=\"name\" style=\"color: " + message.color + "\">" + message.name + "</span>\n                            <span class=\"timestamp\">" + source + message.created_timestamp + "</span>\n                        </div>\n                        <div class=\"content\" data-bot=\"" + message.bot + "\">" + (hits[i].snippet || message.content) + "</div>\n                    </div>\n                </div>\n            ");
+            $hit.attr({
+                "data-backup": hits[i].backup_path || current.backup,
+                "data-chat-id": hits[i].chat_id,
+                "data-message-id": message.message_id,
+            });
             for (var j = 0; j < message.attachments.length; j++) {
                 var attachment = message.attachments[j];
                 if (attachment[1] === "image") {
-                    $("#results .message[data-id=\"" + message.sequential_id + "\"] > div").append("<img src=\"" + attachment[0] + "\" alt=\"attachment\" class=\"attachment\">");
+                    $hit.children("div").append("<img src=\"" + attachment[0] + "\" alt=\"attachment\" class=\"attachment\">");
                 }
                 else if (attachment[1] === "video") {
-                    $("#results .message[data-id=\"" + message.sequential_id + "\"] > div").append("<video src=\"" + attachment[0] + "\" class=\"attachment\" controls></video>");
+                    $hit.children("div").append("<video src=\"" + attachment[0] + "\" class=\"attachment\" controls></video>");
                 }
                 else if (attachment[1] === "audio") {
-                    $("#results .message[data-id=\"" + message.sequential_id + "\"] > div").append("<audio src=\"" + attachment[0] + "\" class=\"attachment\" controls></audio>");
+                    $hit.children("div").append("<audio src=\"" + attachment[0] + "\" class=\"attachment\" controls></audio>");
                 }
             }
+            $hit.on("click", open_search_result);
+            $results.append($hit);
         }
-        $results.prepend("<div id=\"info\">" + result.length + " result" + (result.length === 1 ? "" : "s") + "</div>");
-        $search_button.prop("disabled", false);
-        $("#results").scrollTop(0);
-        $("#results .message").on("click", function () {
-            var clicked = $(this).attr("data-id");
-            $.post("/messages", { sequential_id: $(this).attr("data-id"), position: "around" }).done(function (result) {
-                $messages.empty();
-                display_messages(result, true);
-                document.getElementById(clicked).scrollIntoView();
-                $("#top_loading, #bottom_loading").remove();
+        // The pages are counted in matches, which is more than the hits if some of them couldn't be shown
+        if (offset + SEARCH_PAGE_SIZE < result.total) {
+            $results.append("<button id=\"more_results\">more results</button>");
+            $("#more_results").on("click", function () {
+                $(this).prop("disabled", true);
+                search(query, offset + SEARCH_PAGE_SIZE);
             });
-        });
-    }).fail(function (error) {
+        }
         $search_button.prop("disabled", false);
-        console.log(error);
-        $("#results").html("<div id=\"info\">" + error.statusText + "</div>");
+        if (offset === 0)
+            $results.scrollTop(0);
+    }).fail(function (xhr) {
+        $search_button.prop("disabled", false);
+        $("#more_results").prop("disabled", false);
+        if (offset === 0) {
+            $results.html("<div id=\"info\">" + error_message(xhr) + "</div>");
+        }
+        else {
+            show_toast(error_message(xhr));
+        }
     });
+}
+function open_search_result() {
+    if ($(this).attr("data-backup") !== current.backup) {
+        location.href = permalink($(this).attr("data-backup"), $(this).attr("data-chat-id"), $(this).attr("data-message-id"));
+        return;
+    }
+    if ($(this).attr("data-chat-id") !== current.chat) {
+        jump($(this).attr("data-chat-id"), $(this).attr("data-message-id"));
+        return;
+    }
+    var clicked = $(this).attr("data-id");
+    var message_id = $(this).attr("data-message-id");
+    $.post("/messages", with_current({ sequential_id: clicked, position: "around" })).done(function (result) {
+        $messages.empty();
+        display_messages(result, true);
+        document.getElementById(clicked).scrollIntoView();
+        show_permalink(message_id);
+        $("#top_loading, #bottom_loading").remove();
+    }).fail(function (xhr) { return show_toast(error_message(xhr)); });
+}
+$("#jump_date").on("change", function () {
+    var date = $(this).val();
+    if (date.length === 0)
+        return;
+    $.post("/jump/date", with_current({ date: date })).done(function (result) {
+        if (result.sequential_id === null) {
+            show_toast("This chat has no messages");
+            return;
+        }
+        $messages.empty();
+        display_messages(result.messages, true);
+        var $message = $(document.getElementById(result.sequential_id));
+        if ($message.length) {
+            var $separator = $message.prev(".day_separator");
+            ($separator.length ? $separator : $message)[0].scrollIntoView();
+            show_permalink($message.attr("data-message-id"));
+        }
+        else {
+            show_toast("That message can't be shown");
+        }
+        $("#top_loading, #bottom_loading").remove();
+    }).fail(function (xhr) { return show_toast(error_message(xhr)); });
+});
+$("#search form").on("submit", function (e) {
+    e.preventDefault();
+    var query = $("#query").val();
+    if (query.length === 0) {
+        $("#results").empty();
+        return;
+    }
+    $("#search form button").prop("disabled", true);
+    search(query, 0);
 });
 //# sourceMappingURL=reader.js.map
\ No newline at end of file
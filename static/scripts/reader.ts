@@ -1,3 +1,7 @@
+// The backup and chat the page is showing, and the message it was opened at, if any
+declare let current;
+declare const target;
+
 // Variable to prevent fetching more than one set of messages at once
 let fetching = false;
 
@@ -12,24 +16,53 @@ function scroll_to_bottom() {
     });
 }
 
-scroll_to_bottom();
+const target_message = target && document.querySelector(`#messages [data-message-id="${CSS.escape(target)}"]`);
+if (target_message) {
+    target_message.scrollIntoView();
+} else {
+    // Messages that the reader doesn't show can be linked to, but can't be scrolled to
+    if (target) show_toast("That message can't be shown");
+    scroll_to_bottom();
+}
+
+// Adds the backup and chat the page is showing to the data of a request, so that other tabs
+// changing the cookies doesn't matter
+function with_current(data = {}) {
+    return $.extend({backup: current.backup, chat: current.chat}, data);
+}
+
+// Makes the cookies point to the page, so that it's the one opened next time
+function remember_current() {
+    set_cookie("backup", current.backup);
+    set_cookie("chat", current.chat);
+}
+
+// Show the message that the page is at in the address bar, so that it can be shared
+function show_permalink(message_id) {
+    history.replaceState(null, "", permalink(current.backup, current.chat, message_id));
+}
+
+// The password prompt asks for the backup in the cookies
+$(document).ajaxError((_, xhr) => {
+    if (xhr.status === 401) remember_current();
+});
+
+function escape_html(text) {
+    return $("<span>").text(text).html();
+}
 
 function jump(channel_id, message_id = undefined) {
     if (fetching) return;
     fetching = true;
-    $.post("/jump", {
-        chat_id: channel_id,
+    $.post("/jump", with_current({
+        chat_id: channel_id || current.chat,
         message_id: message_id,
-    }).done(result => {
-        if (!result.name.length) {
-            show_toast("channel not found");
-            fetching = false;
-            return;
-        }
+    })).done(result => {
         // If channel_id is null, we're jumping to a message in the same channel
         if (channel_id) {
-            // Update the cookie
-            document.cookie = "chat=" + channel_id;
+            current.chat = channel_id;
+            // Update the cookies
+            remember_current();
             // Select the new chat in the dropdown
             $("#chat").val(channel_id);
             // Update the chat name and topic
@@ -41,8 +74,17 @@ function jump(channel_id, message_id = undefined) {
         display_messages(result.messages, true);
         // Jump to the relevant message
         if (message_id) {
-            document.querySelector(`[data-message-id="${message_id}"]`).scrollIntoView();
+            const target_message = document.querySelector(`#messages [data-message-id="${CSS.escape(message_id)}"]`);
+            if (target_message) {
+                target_message.scrollIntoView();
+            } else {
+                // The messages around it are still shown, like when the page is opened at it
+                show_toast("That message can't be shown");
+                scroll_to_bottom();
+            }
+            show_permalink(message_id);
         } else {
+            show_permalink(undefined);
             // Create and hide the bottom_loading div because we know we're caught up here
             $messages.append(`<div id="bottom_loading">Loading...</div>`);
             $("#bottom_loading").hide();
@@ -50,6 +92,9 @@ function jump(channel_id, message_id = undefined) {
             scroll_to_bottom();
         }
         fetching = false;
+    }).fail(xhr => {
+        show_toast(error_message(xhr));
+        fetching = false;
     });
 }
 
@@ -153,7 +198,7 @@ function display_messages(messages, ascending) {
                 <div>
                     <div class="title">
                         <span class="name" style="color: ${message.color}">${message.name}</span>
-                        <span class="timestamp">${message.created_timestamp}</span>
+                        <a class="timestamp" href="${permalink(current.backup, current.chat, message.message_id)}">${message.created_timestamp}</a>
                         ${message.bot ? "<span class=\"bot\">BOT</span>" : ""}
                     </div>
                     <div class="content" title="${message.created_timestamp}">${message.content}</div>
@@ -267,7 +312,7 @@ $messages.on("scroll", function() {
         // Store the ID so we can scroll back to it after adding more messages
         const reference_message = $("#messages .message")[0].id;
         // Fetch the messages
-        $.post("/messages", {sequential_id: reference_message, position: "above"}).done(result => {
+        $.post("/messages", with_current({sequential_id: reference_message, position: "above"})).done(result => {
             const $top_loading = $("#top_loading");
             if (result.length === 0) {
                 fetching = false;
@@ -290,12 +335,16 @@ $messages.on("scroll", function() {
             document.getElementById(reference_message).scrollIntoView();
             $top_loading.remove();
             fetching = false;
+        }).fail(xhr => {
+            show_toast(error_message(xhr));
+            $("#top_loading").remove();
+            fetching = false;
         });
     } else if ($messages.scrollTop() + $messages.prop("offsetHeight") >= $messages.prop("scrollHeight") && $("#bottom_loading").length === 0 && !fetching) {
         fetching = true;
         // Add the bottom_loading div
         $messages.append(`<div id="bottom_loading">Loading...</div>`);
-        $.post("/messages", {sequential_id: $("#messages .message:last")[0].id, position: "below"}).done(result => {
+        $.post("/messages", with_current({sequential_id: $("#messages .message:last")[0].id, position: "below"})).done(result => {
             const $bottom_loading = $("#bottom_loading");
             if (result.length === 0) {
                 fetching = false;
@@ -317,10 +366,22 @@ $messages.on("scroll", function() {
             // No need to scroll back to the message the user was at since the elements are added to the bottom
             $bottom_loading.remove();
             fetching = false;
+        }).fail(xhr => {
+            show_toast(error_message(xhr));
+            $("#bottom_loading").remove();
+            fetching = false;
         });
     }
 });
 
+$("#lock_button").on("click", () => {
+    // Forget the decrypted backup and go back to the password prompt
+    $.post("/lock", with_current()).done(() => {
+        remember_current();
+        location.href = "/";
+    }).fail(xhr => show_toast(error_message(xhr)));
+});
+
 // Keyboard shortcuts
 $(window).on("keydown", e => {
     // Ctrl + F to focus the search box
@@ -330,71 +391,160 @@ $(window).on("keydown", e => {
     }
 });
 
-$("#search form").on("submit", e => {
-    e.preventDefault();
+// How many search results are fetched at once
+const SEARCH_PAGE_SIZE = 50;
+
+// Fetches a page of search results and adds them to the end of the results
+function search(query, offset) {
     const $search_button = $("#search form button");
     const $results = $("#results");
-    const query = $("#query").val() as string;
-    $search_button.prop("disabled", true);
-
-    if (query.length === 0) {
-        // If both the query is empty, remove any existing results
-        $results.empty();
-        $search_button.prop("disabled", false);
-        return;
-    }
-    $.post("/search", {string: query, filters: $("#filters").val()}).done(result => {
-        // Clear any existing search results
-        $results.empty();
+    // Either "chat", "chats" (every chat in the backup) or "backups" (every backup)
+    const scope = $("#search_scope").val();
+    const data = {
+        string: query,
+        filters: $("#filters").val(),
+        order: $("#search_order").val(),
+        limit: SEARCH_PAGE_SIZE,
+        offset: offset,
+    };
+    const request = scope === "backups"
+        ? $.post("/search/global", data)
+        : $.post("/search", with_current($.extend({all_chats: scope === "chats"}, data)));
+    request.done(result => {
+        const hits = result.hits;
+        if (offset === 0) {
+            // Clear any existing search results
+            $results.empty();
+            let info = `${result.total} result${result.total === 1 ? "" : "s"}`;
+            if (scope === "backups" && result.failed.length) {
+                // Searching every backup also returns the backups that couldn't be searched
+                info += ` (couldn't search ${result.failed.map(backup => escape_html(backup[0])).join(", ")})`;
+            }
+            $results.append(`<div id="info">${info}</div>`);
+        }
+        $("#more_results").remove();
         // Show the search results
-        for (let i = 0; i < result.length; i++) {
-            let message = result[i];
-            if (message.message_type !== "default") {
-                // Don't render day separators
-                continue;
+        for (let i = 0; i < hits.length; i++) {
+            let message = hits[i].message;
+            // Show where the message is from when searching more than one chat
+            let source = "";
+            if (scope === "backups") {
+                source = `${escape_html(hits[i].backup_name)} #${escape_html(hits[i].chat_name)} `;
+            } else if (scope === "chats") {
+                source = `#${escape_html(hits[i].chat_name)} `;
             }
-            $results.prepend(`
+            let $hit = $(`
                 <div class="message message_container" data-id="${message.sequential_id}">
                     <img src="${message.avatar}" alt="pfp" class="avatar">
                     <div>
                         <div class="title">
                             <span class="name" style="color: ${message.color}">${message.name}</span>
-                            <span class="timestamp">${message.created_timestamp}</span>
+                            <span class="timestamp">${source}${message.created_timestamp}</span>
                         </div>
-                        <div class="content" data-bot="${message.bot}">${message.content}</div>
+                        <div class="content" data-bot="${message.bot}">${hits[i].snippet || message.content}</div>
                     </div>
                 </div>
             `);
+            // These come from the backup, so they're set as attributes instead of being put in the HTML
+            $hit.attr({
+                "data-backup": hits[i].backup_path || current.backup,
+                "data-chat-id": hits[i].chat_id,
+                "data-message-id": message.message_id,
+            });
             // Add any attachments to the message
             for (let j = 0; j < message.attachments.length; j++) {
                 let attachment = message.attachments[j];
                 if (attachment[1] === "image") {
-                    $(`#results .message[data-id="${message.sequential_id}"] > div`).append(`<img src="${attachment[0]}" alt="attachment" class="attachment">`);
+                    $hit.children("div").append(`<img src="${attachment[0]}" alt="attachment" class="attachment">`);
                 } else if (attachment[1] === "video") {
-                    $(`#results .message[data-id="${message.sequential_id}"] > div`).append(`<video src="${attachment[0]}" class="attachment" controls></video>`);
+                    $hit.children("div").append(`<video src="${attachment[0]}" class="attachment" controls></video>`);
                 } else if (attachment[1] === "audio") {
-                    $(`#results .message[data-id="${message.sequential_id}"] > div`).append(`<audio src="${attachment[0]}" class="attachment" controls></audio>`);
+                    $hit.children("div").append(`<audio src="${attachment[0]}" class="attachment" controls></audio>`);
                 }
             }
+            $hit.on("click", open_search_result);
+            $results.append($hit);
         }
-        $results.prepend(`<div id="info">${result.length} result${result.length === 1 ? "" : "s"}</div>`);
-        $search_button.prop("disabled", false);
-        $("#results").scrollTop(0);
-        $("#results .message").on("click", function() {
-            // To keep track of what message to scroll to when a search result is clicked
-            let clicked = $(this).attr("data-id");
-            // Fetch the messages around the clicked message
-            $.post("/messages", {sequential_id: $(this).attr("data-id"), position: "around"}).done(result => {
-                $messages.empty();
-                display_messages(result, true);
-                // Jump to the clicked message
-                document.getElementById(clicked).scrollIntoView();
-                $("#top_loading, #bottom_loading").remove();
+        // The pages are counted in matches, which is more than the hits if some of them couldn't be shown
+        if (offset + SEARCH_PAGE_SIZE < result.total) {
+            // There are more results on the next page
+            $results.append(`<button id="more_results">more results</button>`);
+            $("#more_results").on("click", function() {
+                $(this).prop("disabled", true);
+                search(query, offset + SEARCH_PAGE_SIZE);
             });
-        });
-    }).fail(error => {
+        }
         $search_button.prop("disabled", false);
-        console.log(error);
-        $("#results").html(`<div id="info">${error.statusText}</div>`);
+        if (offset === 0) $results.scrollTop(0);
+    }).fail(xhr => {
+        $search_button.prop("disabled", false);
+        $("#more_results").prop("disabled", false);
+        if (offset === 0) {
+            $results.html(`<div id="info">${error_message(xhr)}</div>`);
+        } else {
+            show_toast(error_message(xhr));
+        }
     });
+}
+
+// Shows the message of a search result in its chat
+function open_search_result() {
+    if ($(this).attr("data-backup") !== current.backup) {
+        // Open the backup the message is in at the message
+        location.href = permalink($(this).attr("data-backup"), $(this).attr("data-chat-id"), $(this).attr("data-message-id"));
+        return;
+    }
+    if ($(this).attr("data-chat-id") !== current.chat) {
+        // Switch to the chat the message is in
+        jump($(this).attr("data-chat-id"), $(this).attr("data-message-id"));
+        return;
+    }
+    // To keep track of what message to scroll to when a search result is clicked
+    let clicked = $(this).attr("data-id");
+    let message_id = $(this).attr("data-message-id");
+    // Fetch the messages around the clicked message
+    $.post("/messages", with_current({sequential_id: clicked, position: "around"})).done(result => {
+        $messages.empty();
+        display_messages(result, true);
+        // Jump to the clicked message
+        document.getElementById(clicked).scrollIntoView();
+        show_permalink(message_id);
+        $("#top_loading, #bottom_loading").remove();
+    }).fail(xhr => show_toast(error_message(xhr)));
+}
+
+$("#jump_date").on("change", function () {
+    const date = $(this).val() as string;
+    if (date.length === 0) return;
+    $.post("/jump/date", with_current({date: date})).done(result => {
+        if (result.sequential_id === null) {
+            show_toast("This chat has no messages");
+            return;
+        }
+        $messages.empty();
+        display_messages(result.messages, true);
+        // Scroll to the day separator above the message if it's the first one of the day
+        const $message = $(document.getElementById(result.sequential_id));
+        if ($message.length) {
+            const $separator = $message.prev(".day_separator");
+            ($separator.length ? $separator : $message)[0].scrollIntoView();
+            show_permalink($message.attr("data-message-id"));
+        } else {
+            show_toast("That message can't be shown");
+        }
+        $("#top_loading, #bottom_loading").remove();
+    }).fail(xhr => show_toast(error_message(xhr)));
+});
+
+$("#search form").on("submit", e => {
+    e.preventDefault();
+    const query = $("#query").val() as string;
+
+    if (query.length === 0) {
+        // If both the query is empty, remove any existing results
+        $("#results").empty();
+        return;
+    }
+    $("#search form button").prop("disabled", true);
+    search(query, 0);
 });